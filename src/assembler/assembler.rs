@@ -22,31 +22,50 @@ Syntax:
 Keywords:
     - NEXT0 -> First Byte of next instruction
     - NEXT1 -> Second Byte of next instruction
+
+Directives:
+    - .alias NAME rX -> NAME can be used instead of rX on the following lines (NAME can't be a register, a keyword, _ or start like a number or a label)
+
+Pseudo-instructions:
+    - LOADLBL rX rY $X -> LOAD rX $X0 then LOAD rY $X1 (takes 2 instructions)
  */
 
 use crate::vm::instruction::Instruction;
-use std::num::ParseIntError;
+use crate::diagnostic::Span;
+use std::num::{ParseIntError, IntErrorKind};
 use std::str::Split;
 use std::fmt::{Debug, Formatter};
-use std::collections::HashMap;
-use crate::vm::machine::REGISTERS;
+use std::collections::{HashMap, BTreeSet};
+use crate::vm::machine::IGNORE;
 
 pub enum AssemblerError {
-    ParseIntError(ParseIntError),
+    BadLiteral { token: String, source: ParseIntError, line: usize },
     MissingArgument,
     WrongArgument,
     UnknownInstruction,
     LabelNotFound,
+    ImmediateOutOfRange { value: String, line: usize },
+    DuplicateLabel { name: String, line: usize },
+    AliasConflict { name: String, line: usize },
+    LabelNotAllowed { line: usize },    // Label used on an argument that can't hold an address
+    InternalInvariantViolated(&'static str),
+    ProgramTooLarge { limit: usize, line: usize },
 }
 
 impl Debug for AssemblerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            AssemblerError::ParseIntError(err) => write!(f, "{:?}", err)?,
+            AssemblerError::BadLiteral { token, source, line } => write!(f, "Bad Literal: '{}' on line {} ({})", token, line, source)?,
             AssemblerError::MissingArgument => write!(f, "Missing Argument")?,
             AssemblerError::WrongArgument => write!(f, "Wrong Argument")?,
             AssemblerError::UnknownInstruction => write!(f, "Unknown Instruction")?,
             AssemblerError::LabelNotFound => write!(f, "Label Not Found")?,
+            AssemblerError::ImmediateOutOfRange { value, line } => write!(f, "Immediate Out Of Range: {} doesn't fit in a byte (0-255) on line {}", value, line)?,
+            AssemblerError::DuplicateLabel { name, line } => write!(f, "Duplicate Label: ${} is already defined (line {})", name, line)?,
+            AssemblerError::AliasConflict { name, line } => write!(f, "Alias Conflict: '{}' is already a label, an alias, a register or reserved (line {})", name, line)?,
+            AssemblerError::LabelNotAllowed { line } => write!(f, "Label Not Allowed: the argument can't hold an address (line {})", line)?,
+            AssemblerError::InternalInvariantViolated(reason) => write!(f, "Internal Invariant Violated: {}", reason)?,
+            AssemblerError::ProgramTooLarge { limit, line } => write!(f, "Program Too Large: more than {} instructions on line {}", limit, line)?,
         }
         Ok(())
     }
@@ -57,42 +76,46 @@ pub enum Argument {
     Register(u8),
 }
 
-fn get_value(parts: &mut Split<&str>, instruction: usize, arg_number: usize, used_labels: &mut Vec<(String, usize, usize, usize)>) -> Result<Argument, AssemblerError> {
+// Parse the number in [token] (without its 2 characters prefix) and check that it fits in a byte
+// A number too large for u64 is out of range too, only malformed numbers are bad literals
+fn parse_immediate(token: &str, radix: u32, line: usize) -> Result<Argument, AssemblerError> {
+    return match u64::from_str_radix(&token[2..token.len()], radix) {
+        Ok(result) if result <= u8::MAX as u64 => Ok(Argument::Byte(result as u8)),
+        Ok(_) => Err(AssemblerError::ImmediateOutOfRange { value: token.to_string(), line }),
+        Err(err) if *err.kind() == IntErrorKind::PosOverflow => Err(AssemblerError::ImmediateOutOfRange { value: token.to_string(), line }),
+        Err(err) => Err(AssemblerError::BadLiteral { token: token.to_string(), source: err, line }),
+    }
+}
+
+fn get_value(parts: &mut Split<&str>, ctx: &mut AssembleContext, arg_number: usize) -> Result<Argument, AssemblerError> {
     return if let Some(text) = parts.next() {
-        if text == "NEXT0" {
-            let address = instruction + 1;
-            Ok(Argument::Byte(((address << 8) & 0xFF) as u8))
+        if let Some(reg) = ctx.aliases.get(text) {
+            Ok(Argument::Register(*reg))
+        } else if text == "NEXT0" {
+            let address = ctx.instruction + 1;
+            Ok(Argument::Byte(((address >> 8) & 0xFF) as u8))
         } else if text == "NEXT1" {
-            let address = instruction + 1;
+            let address = ctx.instruction + 1;
             Ok(Argument::Byte((address & 0xFF) as u8))
         } else if text == "_" {
-            Ok(Argument::Register(REGISTERS as u8))
+            Ok(Argument::Register(IGNORE))
         } else if text.starts_with("0x") {
-            match u8::from_str_radix(&text[2..text.len()], 16) {
-                Ok(result) => Ok(Argument::Byte(result)),
-                Err(err) => Err(AssemblerError::ParseIntError(err)),
-            }
+            parse_immediate(text, 16, ctx.line)
         } else if text.starts_with("0b") {
-            match u8::from_str_radix(&text[2..text.len()], 2) {
-                Ok(result) => Ok(Argument::Byte(result)),
-                Err(err) => Err(AssemblerError::ParseIntError(err)),
-            }
+            parse_immediate(text, 2, ctx.line)
         } else if text.starts_with("0d") {
-            match u8::from_str_radix(&text[2..text.len()], 10) {
-                Ok(result) => Ok(Argument::Byte(result)),
-                Err(err) => Err(AssemblerError::ParseIntError(err)),
-            }
+            parse_immediate(text, 10, ctx.line)
         } else if text.starts_with("r") {
             match u8::from_str_radix(&text[1..text.len()], 16) {
                 Ok(result) => Ok(Argument::Register(result)),
-                Err(err) => Err(AssemblerError::ParseIntError(err)),
+                Err(err) => Err(AssemblerError::BadLiteral { token: text.to_string(), source: err, line: ctx.line }),
             }
         } else if text.starts_with("$") {
             // Label
             if text.ends_with("0") {
-                used_labels.push(((&text[1..(text.len() - 1)]).to_string(), 0, instruction, arg_number));
+                ctx.used_labels.push(((&text[1..(text.len() - 1)]).to_string(), 0, ctx.instruction, arg_number, ctx.line));
             } else if text.ends_with("1") {
-                used_labels.push(((&text[1..(text.len() - 1)]).to_string(), 1, instruction, arg_number));
+                ctx.used_labels.push(((&text[1..(text.len() - 1)]).to_string(), 1, ctx.instruction, arg_number, ctx.line));
             } else {
                 return Err(AssemblerError::WrongArgument);
             }
@@ -105,265 +128,960 @@ fn get_value(parts: &mut Split<&str>, instruction: usize, arg_number: usize, use
     }
 }
 
-pub fn assemble(source: String) -> Result<Vec<Instruction>, AssemblerError> {
-    let mut program = vec![];
-    let mut instruction = 0;
-    let mut labels = HashMap::new();
-    let mut used_labels: Vec<(String, usize, usize, usize)> = vec![];
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub spans: Vec<Span>,               // Source position of each instruction
+    pub labels: HashMap<String, usize>, // Address of each label
+}
 
-    for line in source.lines() {
-        let mut parts = line.split(" ");
-        if let Some(mut part1) = parts.next() {
-            if part1.starts_with("$") {
-                labels.insert(&part1[1..part1.len()], instruction);
-                if let Some(part2) = parts.next() {
-                    part1 = part2;
-                }
+pub struct AssemblerOptions {
+    pub pad_to: Option<(usize, Instruction)>, // Fill the program with the instruction until it reaches the size
+    pub max_instructions: usize, // Most instructions the program can have (padding included)
+}
+
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        return AssemblerOptions {
+            pad_to: None,
+            max_instructions: 0x10000,
+        }
+    }
+}
+
+pub struct AssembleContext {
+    pub line: usize,                                    // Number of lines read
+    pub instruction: usize,                             // Address of the next instruction
+    pub labels: HashMap<String, usize>,                 // Address of each label
+    pub aliases: HashMap<String, u8>,                   // Register of each alias
+    pub used_labels: Vec<(String, usize, usize, usize, usize)>, // (label, byte, instruction, argument, line) to patch once all labels are known
+}
+
+impl AssembleContext {
+
+    pub fn new() -> AssembleContext {
+        return AssembleContext {
+            line: 0,
+            instruction: 0,
+            labels: HashMap::new(),
+            aliases: HashMap::new(),
+            used_labels: vec![],
+        }
+    }
+
+}
+
+// Assemble a single line, returns no instruction if the line doesn't contain one (empty line or comment) and several for a pseudo-instruction
+pub fn assemble_line(line: &str, ctx: &mut AssembleContext) -> Result<Vec<Instruction>, AssemblerError> {
+    ctx.line += 1;
+    if line.is_empty() || line.starts_with("#") {
+        return Ok(vec![]);
+    }
+
+    let mut parts = line.split(" ");
+    let mut part1 = match parts.next() {
+        Some(part1) => part1,
+        None => return Ok(vec![]),
+    };
+    if part1.starts_with("$") {
+        let name = part1[1..part1.len()].to_string();
+        if ctx.labels.contains_key(&name) {
+            return Err(AssemblerError::DuplicateLabel { name, line: ctx.line });
+        }
+        if ctx.aliases.contains_key(&name) {
+            return Err(AssemblerError::AliasConflict { name, line: ctx.line });
+        }
+        ctx.labels.insert(name, ctx.instruction);
+        if let Some(part2) = parts.next() {
+            part1 = part2;
+        }
+    }
+
+    if part1 == ".alias" {
+        let name = match parts.next() {
+            Some(name) => name.to_string(),
+            None => return Err(AssemblerError::MissingArgument),
+        };
+        let is_register = name.starts_with("r") && u8::from_str_radix(&name[1..name.len()], 16).is_ok();
+        // Aliases are resolved before anything else, these names would hide a keyword, a literal or a label
+        let is_reserved = ["NEXT0", "NEXT1", "_"].contains(&name.as_str()) || name.starts_with("$") || name.starts_with(|c: char| c.is_ascii_digit());
+        if is_register || is_reserved || ctx.labels.contains_key(&name) || ctx.aliases.contains_key(&name) {
+            return Err(AssemblerError::AliasConflict { name, line: ctx.line });
+        }
+        if let Argument::Register(reg) = get_value(&mut parts, ctx, 1)? {
+            ctx.aliases.insert(name, reg);
+            return Ok(vec![]);
+        } else {
+            return Err(AssemblerError::WrongArgument);
+        }
+    }
+
+    if part1 == "LOADLBL" {
+        if let Argument::Register(reg_high) = get_value(&mut parts, ctx, 0)? {
+            if let Argument::Register(reg_low) = get_value(&mut parts, ctx, 1)? {
+                let label = match parts.next() {
+                    Some(label) if label.starts_with("$") && label.len() > 1 => label[1..label.len()].to_string(),
+                    Some(_) => return Err(AssemblerError::WrongArgument),
+                    None => return Err(AssemblerError::MissingArgument),
+                };
+                // Each byte is patched in its own LOAD, the label can be defined after this line
+                ctx.used_labels.push((label.clone(), 0, ctx.instruction, 1, ctx.line));
+                ctx.used_labels.push((label, 1, ctx.instruction + 1, 1, ctx.line));
+                ctx.instruction += 2;
+                return Ok(vec![Instruction::Load(reg_high, 0), Instruction::Load(reg_low, 0)]);
+            } else {
+                return Err(AssemblerError::WrongArgument);
             }
+        } else {
+            return Err(AssemblerError::WrongArgument);
+        }
+    }
 
-            match part1 {
-                "LOAD" => {
-                    if let Argument::Register(reg) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Byte(value) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            program.push(Instruction::Load(reg, value));
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+    let instruction = match part1 {
+        "LOAD" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(value) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Load(reg, value)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MOV" => {
+            if let Argument::Register(reg_dst) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_src) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Mov(reg_dst, reg_src)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "ADD" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Add(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "ADD" => {
-                    if let Argument::Register(reg_result) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::Add(reg_result, reg_a, reg_b));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SUB" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Sub(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "SUB" => {
-                    if let Argument::Register(reg_result) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::Sub(reg_result, reg_a, reg_b));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MUL" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Mul(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "MUL" => {
-                    if let Argument::Register(reg_result) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::Mul(reg_result, reg_a, reg_b));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }}
+        "DIV" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Div(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
-                    }}
-                "DIV" => {
-                    if let Argument::Register(reg_result) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::Div(reg_result, reg_a, reg_b));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MOD" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Mod(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "CMP" => {
-                    if let Argument::Register(reg_result) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::Add(reg_result, reg_a, reg_b));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "CMP" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Cmp(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "SPUSH" => {
-                    if let Argument::Register(reg_addr1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_addr2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_value) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::SPush(reg_addr1, reg_addr2, reg_value));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "CMPF" => {
+            if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::CmpF(reg_a, reg_b)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MULW" => {
+            if let Argument::Register(reg_high) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_low) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 2)? {
+                        if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 3)? {
+                            Instruction::MulWide(reg_high, reg_low, reg_a, reg_b)
                         } else {
                             return Err(AssemblerError::WrongArgument);
                         }
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "SCOPY" => {
-                    if let Argument::Register(reg_addr1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_addr2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_value) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::SCopy(reg_addr1, reg_addr2, reg_value));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "FMUL" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::FMul(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "SPOP" => {
-                    if let Argument::Register(reg_addr1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_addr2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_value) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::SPop(reg_addr1, reg_addr2, reg_value));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MIN" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Min(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "SREP" => {
-                    if let Argument::Register(reg_addr1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_addr2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            if let Argument::Register(reg_value) = get_value(&mut parts, instruction, 2, &mut used_labels)? {
-                                program.push(Instruction::SRep(reg_addr1, reg_addr2, reg_value));
-                            } else {
-                                return Err(AssemblerError::WrongArgument);
-                            }
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "MAX" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Max(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "REQ" => {
-                    if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg_b) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            program.push(Instruction::REq(reg_a, reg_b));
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "AND" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::And(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "EQ" => {
-                    if let Argument::Register(reg_a) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Byte(value) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            program.push(Instruction::Eq(reg_a, value));
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "OR" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Or(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "JUMP16" => {
-                    if let Argument::Byte(addr1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Byte(addr2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            program.push(Instruction::Jump16(addr1, addr2));
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "XOR" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Xor(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "RJUMP16" => {
-                    if let Argument::Register(reg1) = get_value(&mut parts, instruction, 0, &mut used_labels)? {
-                        if let Argument::Register(reg2) = get_value(&mut parts, instruction, 1, &mut used_labels)? {
-                            program.push(Instruction::RJump16(reg1, reg2));
-                        } else {
-                            return Err(AssemblerError::WrongArgument);
-                        }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "NOT" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Not(reg_result, reg_a)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SHL" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Shl(reg_result, reg_a, reg_b)
                     } else {
                         return Err(AssemblerError::WrongArgument);
                     }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
-                "HALT" => program.push(Instruction::Halt()),
-                &_ => return Err(AssemblerError::UnknownInstruction)
+            } else {
+                return Err(AssemblerError::WrongArgument);
             }
         }
-        instruction += 1;
-    }
-
-    for (label, b, i, arg) in used_labels {
-        if let Some(ptr) = labels.get(label.as_str()) {
-            let addr = match b {
-                0 => (ptr << 8) & 0xFF,
-                1 => ptr & 0xFF,
-                _ => panic!()
-            } as u8;
-            if let Some(instruction) = program.get_mut(i) {
-                match instruction {
-                    Instruction::Load(_, arg1) => {
-                        match arg {
-                            1 => *arg1 = addr,
-                            _ => panic!()
-                        }
+        "SHR" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::Shr(reg_result, reg_a, reg_b)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
                     }
-                    Instruction::Add(_, _, _) => panic!(),
-                    Instruction::Sub(_, _, _) => panic!(),
-                    Instruction::Mul(_, _, _) => panic!(),
-                    Instruction::Div(_, _, _) => panic!(),
-                    Instruction::Cmp(_, _, _) => panic!(),
-                    Instruction::SPush(_, _, _) => panic!(),
-                    Instruction::SCopy(_, _, _) => panic!(),
-                    Instruction::SPop(_, _, _) => panic!(),
-                    Instruction::SRep(_, _, _) => panic!(),
-                    Instruction::REq(_, arg1) => {
-                        match arg {
-                            1 => *arg1 = addr,
-                            _ => panic!()
-                        }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "CMOV" => {
+            if let Argument::Register(reg_result) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_cond) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::CMov(reg_result, reg_value, reg_cond)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SPUSH" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::SPush(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
                     }
-                    Instruction::Eq(_, _) => panic!(),
-                    Instruction::Jump16(arg0, arg1) => {
-                        match arg {
-                            0 => *arg0 = addr,
-                            1 => *arg1 = addr,
-                            _ => panic!()
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SCOPY" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::SCopy(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SPOP" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::SPop(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "SREP" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::SRep(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "LDI" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::LoadInd(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "STI" => {
+            if let Argument::Register(reg_addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_addr2) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_value) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::StoreInd(reg_addr1, reg_addr2, reg_value)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "REQ" => {
+            if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::REq(reg_a, reg_b)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "EQ" => {
+            if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(value) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Eq(reg_a, value)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JUMP8" => {
+            if let Argument::Byte(addr) = get_value(&mut parts, ctx, 0)? {
+                Instruction::Jump8(addr)
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "RJUMP8" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                Instruction::RJump8(reg)
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JUMP16" => {
+            if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Jump16(addr1, addr2)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "RJUMP16" => {
+            if let Argument::Register(reg1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg2) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::RJump16(reg1, reg2)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JZR" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::JzReg(reg, addr1, addr2)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JNZR" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 2)? {
+                        Instruction::JnzReg(reg, addr1, addr2)
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
+                    }
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JZ" => {
+            if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Jz(addr1, addr2)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JNZ" => {
+            if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Jnz(addr1, addr2)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "JC" => {
+            if let Argument::Byte(addr1) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Byte(addr2) = get_value(&mut parts, ctx, 1)? {
+                    Instruction::Jc(addr1, addr2)
+                } else {
+                    return Err(AssemblerError::WrongArgument);
+                }
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "HALT" => Instruction::Halt(),
+        "IN" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                Instruction::In(reg)
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "OUT" => {
+            if let Argument::Register(reg) = get_value(&mut parts, ctx, 0)? {
+                Instruction::Out(reg)
+            } else {
+                return Err(AssemblerError::WrongArgument);
+            }
+        }
+        "TRAP" => Instruction::Trap(),
+        "CUSTOM" => {
+            if let Argument::Byte(opcode) = get_value(&mut parts, ctx, 0)? {
+                if let Argument::Register(reg_a) = get_value(&mut parts, ctx, 1)? {
+                    if let Argument::Register(reg_b) = get_value(&mut parts, ctx, 2)? {
+                        if let Argument::Register(reg_c) = get_value(&mut parts, ctx, 3)? {
+                            Instruction::Custom(opcode, reg_a, reg_b, reg_c)
+                        } else {
+                            return Err(AssemblerError::WrongArgument);
                         }
+                    } else {
+                        return Err(AssemblerError::WrongArgument);
                     }
-                    Instruction::RJump16(_, _) => panic!(),
-                    Instruction::Halt() => panic!(),
+                } else {
+                    return Err(AssemblerError::WrongArgument);
                 }
+            } else {
+                return Err(AssemblerError::WrongArgument);
             }
+        }
+        &_ => return Err(AssemblerError::UnknownInstruction)
+    };
+    ctx.instruction += 1;
+    return Ok(vec![instruction]);
+}
+
+// Patch the label references collected in the context into the program
+pub fn resolve_labels(program: &mut [Instruction], ctx: &AssembleContext) -> Result<(), AssemblerError> {
+    for (label, b, i, arg, line) in ctx.used_labels.iter() {
+        if let Some(ptr) = ctx.labels.get(label.as_str()) {
+            let addr = match *b {
+                0 => (ptr >> 8) & 0xFF,
+                1 => ptr & 0xFF,
+                _ => return Err(AssemblerError::InternalInvariantViolated("label byte is neither 0 nor 1"))
+            } as u8;
+            let instruction = match program.get_mut(*i) {
+                Some(instruction) => instruction,
+                None => return Err(AssemblerError::InternalInvariantViolated("label used by an instruction that doesn't exist")),
+            };
+            let target = match (instruction, *arg) {
+                (Instruction::Load(_, arg1), 1) => arg1,
+                (Instruction::Jump8(arg0), 0) => arg0,
+                (Instruction::Jump16(arg0, _), 0) => arg0,
+                (Instruction::Jump16(_, arg1), 1) => arg1,
+                (Instruction::JzReg(_, arg1, _), 1) => arg1,
+                (Instruction::JzReg(_, _, arg2), 2) => arg2,
+                (Instruction::JnzReg(_, arg1, _), 1) => arg1,
+                (Instruction::JnzReg(_, _, arg2), 2) => arg2,
+                (Instruction::Jz(arg0, _), 0) | (Instruction::Jnz(arg0, _), 0) | (Instruction::Jc(arg0, _), 0) => arg0,
+                (Instruction::Jz(_, arg1), 1) | (Instruction::Jnz(_, arg1), 1) | (Instruction::Jc(_, arg1), 1) => arg1,
+                _ => return Err(AssemblerError::LabelNotAllowed { line: *line }),
+            };
+            *target = addr;
         } else {
             return Err(AssemblerError::LabelNotFound);
         }
     }
+    return Ok(());
+}
+
+pub fn assemble(source: String) -> Result<Vec<Instruction>, AssemblerError> {
+    return Ok(assemble_program(source)?.instructions);
+}
+
+pub fn assemble_program(source: String) -> Result<Program, AssemblerError> {
+    return assemble_with_options(source, AssemblerOptions::default());
+}
+
+pub fn assemble_with_options(source: String, options: AssemblerOptions) -> Result<Program, AssemblerError> {
+    let mut program = vec![];
+    let mut spans = vec![];
+    let mut ctx = AssembleContext::new();
+
+    for line in source.lines() {
+        for instruction in assemble_line(line, &mut ctx)? {
+            if program.len() >= options.max_instructions {
+                return Err(AssemblerError::ProgramTooLarge { limit: options.max_instructions, line: ctx.line });
+            }
+            program.push(instruction);
+            spans.push(Span {
+                line: ctx.line,
+                col_start: 1,
+                col_end: line.chars().count() + 1,
+            });
+        }
+    }
+
+    resolve_labels(&mut program, &ctx)?;
+
+    // Padding is added after the labels are resolved so it doesn't move them
+    if let Some((size, filler)) = options.pad_to {
+        if size > options.max_instructions {
+            return Err(AssemblerError::ProgramTooLarge { limit: options.max_instructions, line: ctx.line });
+        }
+        while program.len() < size {
+            program.push(filler.clone());
+        }
+    }
+
+    return Ok(Program {
+        instructions: program,
+        spans,
+        labels: ctx.labels,
+    });
+}
+
+// Format the program with the address of each instruction (the value to use with JUMP16)
+pub fn listing(program: &[Instruction]) -> String {
+    let mut result = String::new();
+    for (address, instruction) in program.iter().enumerate() {
+        result.push_str(&format!("[{:04X}] {:?}\n", address, instruction));
+    }
+    return result;
+}
+
+// Format the program as assembly that assemble can read back, jump targets get a label ($LXXXX_ for the address XXXX)
+pub fn disassemble(program: &[Instruction]) -> String {
+    let mut targets = BTreeSet::new();
+    for instruction in program.iter() {
+        match *instruction {
+            Instruction::Jump8(byte) => { targets.insert(byte as usize); }
+            Instruction::Jump16(byte1, byte2) | Instruction::JzReg(_, byte1, byte2) | Instruction::JnzReg(_, byte1, byte2) |
+            Instruction::Jz(byte1, byte2) | Instruction::Jnz(byte1, byte2) | Instruction::Jc(byte1, byte2) => {
+                targets.insert(((byte1 as usize) << 8) | (byte2 as usize));
+            }
+            _ => {}
+        }
+    }
+    // A label can only be defined on an instruction
+    let targets: BTreeSet<usize> = targets.into_iter().filter(|target| *target < program.len()).collect();
+    let label = |byte1: u8, byte2: u8| -> Option<String> {
+        let target = ((byte1 as usize) << 8) | (byte2 as usize);
+        return if targets.contains(&target) {
+            Some(format!("L{:04X}_", target))
+        } else {
+            None
+        }
+    };
 
-    return Ok(program);
-}
\ No newline at end of file
+    let mut result = String::new();
+    for (address, instruction) in program.iter().enumerate() {
+        if targets.contains(&address) {
+            result.push_str(&format!("$L{:04X}_ ", address));
+        }
+        let text = match *instruction {
+            Instruction::Jump8(byte) => label(0, byte).map(|name| format!("JUMP8 ${}1", name)),
+            Instruction::Jump16(byte1, byte2) => label(byte1, byte2).map(|name| format!("JUMP16 ${}0 ${}1", name, name)),
+            Instruction::JzReg(reg, byte1, byte2) => label(byte1, byte2).map(|name| format!("JZR r{:X} ${}0 ${}1", reg, name, name)),
+            Instruction::JnzReg(reg, byte1, byte2) => label(byte1, byte2).map(|name| format!("JNZR r{:X} ${}0 ${}1", reg, name, name)),
+            Instruction::Jz(byte1, byte2) | Instruction::Jnz(byte1, byte2) | Instruction::Jc(byte1, byte2) => {
+                label(byte1, byte2).map(|name| format!("{} ${}0 ${}1", instruction.mnemonic(), name, name))
+            }
+            _ => None,
+        };
+        match text {
+            Some(text) => result.push_str(&text),
+            None => result.push_str(&format!("{:?}", instruction)),
+        }
+        result.push('\n');
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::machine::{VM, HaltReason};
+
+    #[test]
+    fn immediate_too_large_is_out_of_range() {
+        assert!(matches!(assemble("LOAD r0 0x1FF".to_string()), Err(AssemblerError::ImmediateOutOfRange { line: 1, .. })));
+        assert!(matches!(assemble("LOAD r0 0d256".to_string()), Err(AssemblerError::ImmediateOutOfRange { line: 1, .. })));
+        assert!(matches!(assemble("LOAD r0 0x1FFFFFFFFFFFFFFFFFF".to_string()), Err(AssemblerError::ImmediateOutOfRange { line: 1, .. })));
+        assert!(matches!(assemble("LOAD r0 0xFF".to_string()), Ok(program) if program == vec![Instruction::Load(0, 0xFF)]));
+    }
+
+    #[test]
+    fn malformed_immediate_is_a_bad_literal() {
+        assert!(matches!(assemble("HALT\nLOAD r0 0xZZ".to_string()), Err(AssemblerError::BadLiteral { ref token, line: 2, .. }) if token == "0xZZ"));
+        assert!(matches!(assemble("LOAD r0 0b102".to_string()), Err(AssemblerError::BadLiteral { ref token, line: 1, .. }) if token == "0b102"));
+    }
+
+    #[test]
+    fn cmpf_assembles() {
+        assert!(matches!(assemble("CMPF r0 r1".to_string()), Ok(program) if program == vec![Instruction::CmpF(0, 1)]));
+        assert!(matches!(assemble("CMPF r0 0x01".to_string()), Err(AssemblerError::WrongArgument)));
+    }
+
+    #[test]
+    fn label_on_an_argument_that_is_not_an_address() {
+        assert!(matches!(assemble("HALT\n$x EQ r0 $x1".to_string()), Err(AssemblerError::LabelNotAllowed { line: 2 })));
+        assert!(matches!(assemble("$x JUMP16 $x0 $x1".to_string()), Ok(program) if program == vec![Instruction::Jump16(0, 0)]));
+    }
+
+    #[test]
+    fn alias_assembles_like_the_register() {
+        let aliased = assemble(".alias counter r3\nLOAD counter 0x05\nADD counter counter counter\nOUT counter".to_string()).unwrap();
+        let raw = assemble("LOAD r3 0x05\nADD r3 r3 r3\nOUT r3".to_string()).unwrap();
+        assert_eq!(aliased, raw);
+    }
+
+    #[test]
+    fn alias_cannot_hide_a_keyword_or_a_literal() {
+        for name in ["_", "NEXT0", "NEXT1", "0x01", "0d5", "7", "$x", "r3"].iter() {
+            let source = format!("HALT\n.alias {} r1", name);
+            assert!(matches!(assemble(source), Err(AssemblerError::AliasConflict { line: 2, .. })), "{}", name);
+        }
+        assert!(matches!(assemble("$x HALT\n.alias x r1".to_string()), Err(AssemblerError::AliasConflict { line: 2, .. })));
+    }
+
+    #[test]
+    fn next_bytes_cross_a_page() {
+        // The LOAD is at 0x00FF so the next instruction is at 0x0100
+        let source = format!("{}LOAD r0 NEXT0\nLOAD r1 NEXT1", "HALT\n".repeat(0xFF));
+        let program = assemble(source).unwrap();
+        assert_eq!(program[0xFF], Instruction::Load(0, 0x01));
+        assert_eq!(program[0x100], Instruction::Load(1, 0x01));
+        let source = format!("{}JUMP16 NEXT0 NEXT1", "HALT\n".repeat(0x1FE));
+        assert_eq!(assemble(source).unwrap()[0x1FE], Instruction::Jump16(0x01, 0xFF));
+    }
+
+    // Compare the listing of the fixture res/snapshots/[name].mvm with res/snapshots/[name].expected
+    // Set UPDATE_SNAPSHOTS=1 to write the current listing as the expected one instead
+    fn check_snapshot(name: &str, source: &str, expected: &str) {
+        let actual = listing(&assemble(source.to_string()).unwrap());
+        if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+            let path = std::path::Path::new(file!()).parent().unwrap().join(format!("../../res/snapshots/{}.expected", name));
+            std::fs::write(path, actual).unwrap();
+            return;
+        }
+        assert_eq!(actual, expected, "The listing of {}.mvm changed, run the tests with UPDATE_SNAPSHOTS=1 if it's intended", name);
+    }
+
+    #[test]
+    fn instructions_snapshot() {
+        check_snapshot("instructions", include_str!("../../res/snapshots/instructions.mvm"), include_str!("../../res/snapshots/instructions.expected"));
+    }
+
+    #[test]
+    fn labels_snapshot() {
+        check_snapshot("labels", include_str!("../../res/snapshots/labels.mvm"), include_str!("../../res/snapshots/labels.expected"));
+    }
+
+    #[test]
+    fn cmp_assembles_to_cmp() {
+        assert_eq!(assemble("CMP r0 r1 r2".to_string()).unwrap(), vec![Instruction::Cmp(0, 1, 2)]);
+    }
+
+    #[test]
+    fn label_past_0xff_patches_both_bytes() {
+        // $far is instruction 0x0102, used before and after it is defined
+        let source = format!("JUMP16 $far0 $far1\n{}$far HALT\nJZR r0 $far0 $far1", "HALT\n".repeat(0x0101));
+        let program = assemble(source).unwrap();
+        assert_eq!(program[0], Instruction::Jump16(0x01, 0x02));
+        assert_eq!(program[0x0103], Instruction::JzReg(0, 0x01, 0x02));
+    }
+
+    #[test]
+    fn assemble_program_returns_the_labels() {
+        let program = assemble_program("$start LOAD r0 0x01\n# comment\nOUT r0\n$end HALT".to_string()).unwrap();
+        assert_eq!(program.instructions.len(), 3);
+        assert_eq!(program.labels.len(), 2);
+        assert_eq!(program.labels.get("start"), Some(&0));
+        assert_eq!(program.labels.get("end"), Some(&2));
+    }
+
+    #[test]
+    fn pad_to_fills_the_program() {
+        // There is no NOP, HALT is the filler
+        let options = AssemblerOptions { pad_to: Some((8, Instruction::Halt())), ..AssemblerOptions::default() };
+        let program = assemble_with_options("LOAD r0 0x01\nOUT r0\nLOAD r1 0x02".to_string(), options).unwrap().instructions;
+        assert_eq!(program.len(), 8);
+        assert_eq!(program[..3], [Instruction::Load(0, 0x01), Instruction::Out(0), Instruction::Load(1, 0x02)]);
+        assert!(program[3..].iter().all(|instruction| *instruction == Instruction::Halt()));
+    }
+
+    #[test]
+    fn assemble_line_by_line_matches_assemble() {
+        let source = ".alias counter r3\n$loop LOAD counter 0x05\n# comment\n\nLOADLBL r0 r1 $end\nJZR counter $end0 $end1\nJUMP16 $loop0 $loop1\n$end HALT";
+        let mut ctx = AssembleContext::new();
+        let mut program = vec![];
+        for line in source.lines() {
+            program.extend(assemble_line(line, &mut ctx).unwrap());
+        }
+        resolve_labels(&mut program, &ctx).unwrap();
+        assert_eq!(program, assemble(source.to_string()).unwrap());
+        assert_eq!(ctx.line, 8);
+    }
+
+    #[test]
+    fn label_defined_twice_is_a_duplicate() {
+        let error = assemble("$loop HALT\nOUT r0\n$loop HALT".to_string());
+        assert!(matches!(error, Err(AssemblerError::DuplicateLabel { ref name, line: 3 }) if name == "loop"));
+        let program = assemble_program("$loop HALT\n$loop2 JUMP16 $loop0 $loop1".to_string()).unwrap();
+        assert_eq!(program.instructions, vec![Instruction::Halt(), Instruction::Jump16(0, 0)]);
+        assert_eq!((program.labels.get("loop"), program.labels.get("loop2")), (Some(&0), Some(&1)));
+    }
+
+    #[test]
+    fn loadlbl_loads_both_address_bytes() {
+        // $far is instruction 0x0102, the filler in between would set r2
+        let source = format!("LOADLBL r0 r1 $far\nRJUMP16 r0 r1\n{}$far LOAD r3 0x01\nHALT", "LOAD r2 0xFF\n".repeat(0x0102 - 3));
+        let mut vm = VM::new(assemble(source).unwrap());
+        assert_eq!(vm.run(), HaltReason::Halted);
+        assert_eq!((vm.register(0), vm.register(1)), (Some(0x01), Some(0x02)));
+        assert_eq!((vm.register(2), vm.register(3)), (Some(0), Some(1)));
+    }
+
+    #[test]
+    fn max_instructions_limit() {
+        let options = |max_instructions| AssemblerOptions { max_instructions, ..AssemblerOptions::default() };
+        let source = "LOADLBL r0 r1 $end\nOUT r0\n$end HALT";
+        assert_eq!(assemble_with_options(source.to_string(), options(4)).unwrap().instructions.len(), 4);
+        assert!(matches!(assemble_with_options(source.to_string(), options(3)), Err(AssemblerError::ProgramTooLarge { limit: 3, line: 3 })));
+        // LOADLBL is the first instruction over the limit
+        assert!(matches!(assemble_with_options(source.to_string(), options(1)), Err(AssemblerError::ProgramTooLarge { limit: 1, line: 1 })));
+        let padded = AssemblerOptions { pad_to: Some((5, Instruction::Halt())), max_instructions: 4 };
+        assert!(matches!(assemble_with_options(source.to_string(), padded), Err(AssemblerError::ProgramTooLarge { limit: 4, .. })));
+    }
+
+    #[test]
+    fn disassemble_round_trips() {
+        let source = "$loop LOAD r0 0x05\nSPUSH _ r1 r0\nJZR r0 $end0 $end1\nEQ r0 0x01\nJUMP8 $loop1\nJUMP16 $loop0 $loop1\nCUSTOM 0x01 r0 r1 r2\n$end HALT";
+        let program = assemble(source.to_string()).unwrap();
+        let text = disassemble(&program);
+        assert_eq!(assemble(text.clone()).unwrap(), program, "{}", text);
+        // Jump targets get a label, the other values stay literal
+        assert!(text.starts_with("$L0000_ LOAD r0 0x05\n"), "{}", text);
+        assert!(text.contains("JZR r0 $L0007_0 $L0007_1"), "{}", text);
+        // A target out of the program stays an address
+        let program = vec![Instruction::Jump16(0x01, 0x00), Instruction::Halt()];
+        assert_eq!(assemble(disassemble(&program)).unwrap(), program);
+    }
+
+}
@@ -0,0 +1,153 @@
+/*
+Goal: Split a program into basic blocks.
+    - A block starts at the beginning of the program, at a jump target or after a jump/skip/halt
+    - A block ends before the next block start
+    - Successors are the blocks that can be executed right after the block
+    - Export the blocks as a Graphviz graph
+ */
+
+use crate::vm::instruction::Instruction;
+use std::collections::BTreeSet;
+
+pub struct Block<'a> {
+    pub start: usize,                       // Address of the first instruction of the block
+    pub instructions: &'a [Instruction],
+    pub successors: Vec<usize>,             // Index (in the block list) of the blocks that can follow this one
+}
+
+fn jump_target(byte1: u8, byte2: u8) -> usize {
+    return ((byte1 as usize) << 8) | (byte2 as usize);
+}
+
+// Address of the instructions that can be executed after the instruction at [address]
+fn next_addresses(program: &[Instruction], address: usize) -> Vec<usize> {
+    return match program[address] {
+        Instruction::Jump8(byte) => vec![byte as usize],
+        Instruction::Jump16(byte1, byte2) => vec![jump_target(byte1, byte2)],
+        Instruction::RJump8(_) | Instruction::RJump16(_, _) => vec![], // Target is only known at runtime
+        Instruction::Halt() => vec![],
+        Instruction::REq(_, _) | Instruction::Eq(_, _) => vec![address + 1, address + 2],
+        Instruction::JzReg(_, byte1, byte2) | Instruction::JnzReg(_, byte1, byte2) => vec![jump_target(byte1, byte2), address + 1],
+        Instruction::Jz(byte1, byte2) | Instruction::Jnz(byte1, byte2) | Instruction::Jc(byte1, byte2) => vec![jump_target(byte1, byte2), address + 1],
+        _ => vec![address + 1],
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    return match instruction {
+        Instruction::Jump8(_) | Instruction::RJump8(_) => true,
+        Instruction::Jump16(_, _) | Instruction::RJump16(_, _) | Instruction::Halt() => true,
+        Instruction::REq(_, _) | Instruction::Eq(_, _) => true,
+        Instruction::JzReg(_, _, _) | Instruction::JnzReg(_, _, _) => true,
+        Instruction::Jz(_, _) | Instruction::Jnz(_, _) | Instruction::Jc(_, _) => true,
+        _ => false,
+    }
+}
+
+pub fn basic_blocks(program: &[Instruction]) -> Vec<Block<'_>> {
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+    for (address, instruction) in program.iter().enumerate() {
+        if ends_block(instruction) {
+            leaders.insert(address + 1);
+            for next in next_addresses(program, address) {
+                leaders.insert(next);
+            }
+        }
+    }
+    let leaders: Vec<usize> = leaders.into_iter().filter(|address| *address < program.len()).collect();
+
+    let mut blocks = vec![];
+    for (i, start) in leaders.iter().enumerate() {
+        let end = if i + 1 < leaders.len() { leaders[i + 1] } else { program.len() };
+        let successors = next_addresses(program, end - 1).iter()
+            .filter_map(|address| leaders.iter().position(|leader| leader == address))
+            .collect();
+        blocks.push(Block {
+            start: *start,
+            instructions: &program[*start..end],
+            successors,
+        });
+    }
+    return blocks;
+}
+
+// Graphviz DOT graph of the program, one node per block listing its instructions and one edge per successor
+pub fn to_dot(program: &[Instruction]) -> String {
+    let blocks = basic_blocks(program);
+    let mut result = String::from("digraph program {\n    node [shape=box, fontname=monospace];\n");
+    for (i, block) in blocks.iter().enumerate() {
+        let mut label = String::new();
+        for (offset, instruction) in block.instructions.iter().enumerate() {
+            label.push_str(&format!("[{:04X}] {:?}\\l", block.start + offset, instruction));
+        }
+        result.push_str(&format!("    b{} [label=\"{}\"];\n", i, label));
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        for successor in block.successors.iter() {
+            result.push_str(&format!("    b{} -> b{};\n", i, successor));
+        }
+    }
+    result.push_str("}\n");
+    return result;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(blocks: &[Block]) -> Vec<(usize, usize, Vec<usize>)> {
+        return blocks.iter().map(|block| (block.start, block.instructions.len(), block.successors.clone())).collect();
+    }
+
+    #[test]
+    fn forward_branch_splits_blocks() {
+        let program = vec![
+            Instruction::Load(0, 1),
+            Instruction::JzReg(0, 0, 4),
+            Instruction::Load(1, 2),
+            Instruction::Out(1),
+            Instruction::Halt(),
+        ];
+        assert_eq!(bounds(&basic_blocks(&program)), vec![
+            (0, 2, vec![2, 1]),
+            (2, 2, vec![2]),
+            (4, 1, vec![]),
+        ]);
+    }
+
+    #[test]
+    fn skip_successors_stay_in_the_program() {
+        let program = vec![
+            Instruction::Load(0, 1),
+            Instruction::Eq(0, 1),
+            Instruction::Out(0),
+            Instruction::Halt(),
+        ];
+        assert_eq!(bounds(&basic_blocks(&program)), vec![
+            (0, 2, vec![1, 2]),
+            (2, 1, vec![2]),
+            (3, 1, vec![]),
+        ]);
+        // The skipped instruction or the one after it can be past the end
+        let program = vec![Instruction::Load(0, 1), Instruction::REq(0, 0), Instruction::Halt()];
+        assert_eq!(bounds(&basic_blocks(&program)), vec![(0, 2, vec![1]), (2, 1, vec![])]);
+        let program = vec![Instruction::Load(0, 1), Instruction::Eq(0, 1)];
+        assert_eq!(bounds(&basic_blocks(&program)), vec![(0, 2, vec![])]);
+    }
+
+    #[test]
+    fn dot_has_a_node_per_block_and_an_edge_per_successor() {
+        // if r0 { OUT r1 } then HALT: 3 blocks, the branch has 2 edges and the body 1
+        let program = vec![
+            Instruction::Load(0, 1),
+            Instruction::JzReg(0, 0, 3),
+            Instruction::Out(1),
+            Instruction::Halt(),
+        ];
+        let dot = to_dot(&program);
+        assert!(dot.starts_with("digraph program {"));
+        assert_eq!(dot.lines().filter(|line| line.contains("[label=")).count(), 3);
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).map(|line| line.trim()).collect();
+        assert_eq!(edges, vec!["b0 -> b2;", "b0 -> b1;", "b1 -> b2;"]);
+    }
+}
@@ -1 +1,4 @@
-pub mod assembler;
\ No newline at end of file
+pub mod assembler;
+pub mod blocks;
+pub mod verifier;
+pub mod peephole;
\ No newline at end of file
@@ -0,0 +1,171 @@
+/*
+Goal: Remove the instructions of a program that don't change its behavior.
+    - A LOAD of the value a register already holds is removed (values are only followed inside a basic block)
+    - Jump targets are moved to the new address of the instruction they pointed to
+    - A program with RJUMP8/RJUMP16 is kept as is, its targets are only known at runtime
+
+Eg: LOAD r0 0x07, OUT r0, LOAD r0 0x07, OUT r0 -> LOAD r0 0x07, OUT r0, OUT r0
+ */
+
+use crate::assembler::blocks::basic_blocks;
+use crate::assembler::verifier::writes;
+use crate::diagnostic::Span;
+use crate::vm::instruction::Instruction;
+use crate::vm::machine::REGISTERS;
+
+// Whether each instruction can be removed
+fn redundant(program: &[Instruction]) -> Vec<bool> {
+    let mut removed = vec![false; program.len()];
+    for block in basic_blocks(program) {
+        let mut known: [Option<u8>; REGISTERS] = [None; REGISTERS];
+        for (offset, instruction) in block.instructions.iter().enumerate() {
+            if let Instruction::Load(reg, value) = *instruction {
+                if (reg as usize) < REGISTERS && known[reg as usize] == Some(value) {
+                    removed[block.start + offset] = true;
+                    continue;
+                }
+            }
+            for reg in writes(instruction) {
+                if (reg as usize) < REGISTERS {
+                    known[reg as usize] = None;
+                }
+            }
+            if let Instruction::Load(reg, value) = *instruction {
+                if (reg as usize) < REGISTERS {
+                    known[reg as usize] = Some(value);
+                }
+            }
+        }
+    }
+    return removed;
+}
+
+// The program without the removed instructions, None if a jump target doesn't fit its instruction anymore
+fn remove(program: &[Instruction], removed: &[bool]) -> Option<Vec<Instruction>> {
+    // New address of each instruction, and of the end of the program
+    let mut addresses = Vec::with_capacity(program.len() + 1);
+    let mut address = 0;
+    for is_removed in removed.iter() {
+        addresses.push(address);
+        if !is_removed {
+            address += 1;
+        }
+    }
+    addresses.push(address);
+    let target = |byte1: u8, byte2: u8| -> Option<(u8, u8)> {
+        let target = *addresses.get(((byte1 as usize) << 8) | (byte2 as usize))?;
+        return Some(((target >> 8) as u8, (target & 0xFF) as u8));
+    };
+
+    let mut result = vec![];
+    for (instruction, is_removed) in program.iter().zip(removed.iter()) {
+        if *is_removed {
+            continue;
+        }
+        result.push(match *instruction {
+            Instruction::Jump8(byte) => match target(0, byte)? {
+                (0, byte) => Instruction::Jump8(byte),
+                _ => return None,
+            },
+            Instruction::Jump16(byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::Jump16(byte1, byte2)
+            }
+            Instruction::JzReg(reg, byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::JzReg(reg, byte1, byte2)
+            }
+            Instruction::JnzReg(reg, byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::JnzReg(reg, byte1, byte2)
+            }
+            Instruction::Jz(byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::Jz(byte1, byte2)
+            }
+            Instruction::Jnz(byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::Jnz(byte1, byte2)
+            }
+            Instruction::Jc(byte1, byte2) => {
+                let (byte1, byte2) = target(byte1, byte2)?;
+                Instruction::Jc(byte1, byte2)
+            }
+            _ => instruction.clone(),
+        });
+    }
+    return Some(result);
+}
+
+pub fn peephole(program: &[Instruction]) -> Vec<Instruction> {
+    return peephole_with_spans(program, &[]).0;
+}
+
+// Same as peephole, the spans of the removed instructions are removed too
+pub fn peephole_with_spans(program: &[Instruction], spans: &[Span]) -> (Vec<Instruction>, Vec<Span>) {
+    let unchanged = (program.to_vec(), spans.to_vec());
+    if program.iter().any(|instruction| matches!(instruction, Instruction::RJump8(_) | Instruction::RJump16(_, _))) {
+        return unchanged;
+    }
+    let removed = redundant(program);
+    return match remove(program, &removed) {
+        Some(result) => {
+            let spans = spans.iter().zip(removed.iter())
+                .filter(|(_, is_removed)| !**is_removed)
+                .map(|(span, _)| *span)
+                .collect();
+            (result, spans)
+        }
+        None => unchanged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_of_a_known_value_is_removed() {
+        let program = vec![
+            Instruction::Load(0, 7),
+            Instruction::Out(0),
+            Instruction::Load(0, 7),
+            Instruction::Out(0),
+            Instruction::Load(0, 8),
+            Instruction::Add(0, 0, 0),
+            Instruction::Load(0, 8),
+        ];
+        assert_eq!(peephole(&program), vec![
+            Instruction::Load(0, 7),
+            Instruction::Out(0),
+            Instruction::Out(0),
+            Instruction::Load(0, 8),
+            Instruction::Add(0, 0, 0),
+            Instruction::Load(0, 8),
+        ]);
+    }
+
+    #[test]
+    fn jumps_follow_the_moved_instructions() {
+        // The LOAD at 0x0003 starts a block so it isn't removed, the jumps to it and to the end move back by 1
+        let program = vec![
+            Instruction::Load(0, 0),
+            Instruction::Load(0, 0),
+            Instruction::JzReg(0, 0x00, 0x03),
+            Instruction::Load(0, 0),
+            Instruction::Jump16(0x00, 0x05),
+        ];
+        let spans: Vec<Span> = (1..=5).map(|line| Span { line, col_start: 1, col_end: 2 }).collect();
+        let (result, result_spans) = peephole_with_spans(&program, &spans);
+        assert_eq!(result, vec![
+            Instruction::Load(0, 0),
+            Instruction::JzReg(0, 0x00, 0x02),
+            Instruction::Load(0, 0),
+            Instruction::Jump16(0x00, 0x04),
+        ]);
+        assert_eq!(result_spans.iter().map(|span| span.line).collect::<Vec<usize>>(), vec![1, 3, 4, 5]);
+        // Runtime targets can't be moved
+        let program = vec![Instruction::Load(0, 0), Instruction::Load(0, 0), Instruction::RJump8(0)];
+        assert_eq!(peephole(&program), program);
+    }
+}
@@ -0,0 +1,146 @@
+/*
+Goal: Find registers that can be read before being written.
+    - Compute for each basic block the registers written on every path reaching it
+    - Walk each block and report the first read of a register that isn't guaranteed to be written
+ */
+
+use crate::assembler::blocks::basic_blocks;
+use crate::vm::instruction::Instruction;
+use crate::vm::machine::{Register, REGISTERS};
+use std::fmt::{Debug, Formatter};
+
+pub enum VerifyError {
+    UninitializedRegister { instruction: usize, register: Register },
+}
+
+impl Debug for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::UninitializedRegister { instruction, register } => write!(f, "Uninitialized Register: r{:X} may be read before being written at 0x{:04X}", register, instruction)?,
+        }
+        Ok(())
+    }
+}
+
+const ALL_REGISTERS: u32 = (1 << REGISTERS) - 1;
+
+fn reads(instruction: &Instruction) -> Vec<Register> {
+    return match instruction {
+        Instruction::Add(_, a, b) | Instruction::Sub(_, a, b) | Instruction::Mul(_, a, b) | Instruction::Div(_, a, b) | Instruction::Mod(_, a, b) |
+        Instruction::Cmp(_, a, b) | Instruction::FMul(_, a, b) | Instruction::Min(_, a, b) | Instruction::Max(_, a, b) |
+        Instruction::And(_, a, b) | Instruction::Or(_, a, b) | Instruction::Xor(_, a, b) |
+        Instruction::Shl(_, a, b) | Instruction::Shr(_, a, b) => vec![*a, *b],
+        Instruction::Not(_, a) | Instruction::Mov(_, a) => vec![*a],
+        Instruction::MulWide(_, _, a, b) => vec![*a, *b],
+        // The result keeps its value when the condition is 0
+        Instruction::CMov(result, value, cond) => vec![*result, *value, *cond],
+        Instruction::SPush(_, _, value) => vec![*value],
+        Instruction::SCopy(addr1, addr2, _) | Instruction::SPop(addr1, addr2, _) | Instruction::LoadInd(addr1, addr2, _) => vec![*addr1, *addr2],
+        Instruction::SRep(addr1, addr2, value) | Instruction::StoreInd(addr1, addr2, value) => vec![*addr1, *addr2, *value],
+        Instruction::CmpF(a, b) | Instruction::REq(a, b) | Instruction::RJump16(a, b) => vec![*a, *b],
+        Instruction::Eq(a, _) | Instruction::RJump8(a) | Instruction::Out(a) | Instruction::JzReg(a, _, _) | Instruction::JnzReg(a, _, _) => vec![*a],
+        Instruction::Jz(_, _) | Instruction::Jnz(_, _) | Instruction::Jc(_, _) | Instruction::In(_) |
+        Instruction::Load(_, _) | Instruction::Jump8(_) | Instruction::Jump16(_, _) | Instruction::Halt() | Instruction::Trap() => vec![],
+        // The handler is unknown here, assume it only writes its registers
+        Instruction::Custom(_, _, _, _) => vec![],
+    }
+}
+
+pub(crate) fn writes(instruction: &Instruction) -> Vec<Register> {
+    return match instruction {
+        Instruction::Load(result, _) | Instruction::Mov(result, _) | Instruction::In(result) => vec![*result],
+        Instruction::Add(result, _, _) | Instruction::Sub(result, _, _) | Instruction::Mul(result, _, _) | Instruction::Div(result, _, _) | Instruction::Mod(result, _, _) |
+        Instruction::Cmp(result, _, _) | Instruction::FMul(result, _, _) | Instruction::Min(result, _, _) | Instruction::Max(result, _, _) |
+        Instruction::And(result, _, _) | Instruction::Or(result, _, _) | Instruction::Xor(result, _, _) | Instruction::Not(result, _) |
+        Instruction::Shl(result, _, _) | Instruction::Shr(result, _, _) => vec![*result],
+        Instruction::MulWide(high, low, _, _) => vec![*high, *low],
+        Instruction::CMov(result, _, _) => vec![*result],
+        Instruction::SPush(addr1, addr2, _) => vec![*addr1, *addr2],
+        Instruction::SCopy(_, _, value) | Instruction::SPop(_, _, value) | Instruction::LoadInd(_, _, value) => vec![*value],
+        Instruction::Custom(_, a, b, c) => vec![*a, *b, *c],
+        _ => vec![],
+    }
+}
+
+fn mask(registers: Vec<Register>) -> u32 {
+    return registers.iter()
+        .filter(|reg| (**reg as usize) < REGISTERS)
+        .fold(0, |mask, reg| mask | (1 << reg));
+}
+
+pub fn verify(program: &[Instruction]) -> Result<(), VerifyError> {
+    let blocks = basic_blocks(program);
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut predecessors: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+    for (i, block) in blocks.iter().enumerate() {
+        for successor in block.successors.iter() {
+            predecessors[*successor].push(i);
+        }
+    }
+    let block_writes: Vec<u32> = blocks.iter()
+        .map(|block| block.instructions.iter().fold(0, |written, instruction| written | mask(writes(instruction))))
+        .collect();
+
+    // Registers written on every path reaching the start of each block
+    let mut written_in: Vec<u32> = vec![ALL_REGISTERS; blocks.len()];
+    written_in[0] = 0;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 1..blocks.len() {
+            let written = predecessors[i].iter()
+                .fold(ALL_REGISTERS, |written, predecessor| written & (written_in[*predecessor] | block_writes[*predecessor]));
+            if written != written_in[i] {
+                written_in[i] = written;
+                changed = true;
+            }
+        }
+    }
+
+    for (i, block) in blocks.iter().enumerate() {
+        let mut written = written_in[i];
+        for (offset, instruction) in block.instructions.iter().enumerate() {
+            for register in reads(instruction) {
+                if (register as usize) < REGISTERS && written & (1 << register) == 0 {
+                    return Err(VerifyError::UninitializedRegister { instruction: block.start + offset, register });
+                }
+            }
+            written |= mask(writes(instruction));
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_written_on_one_path_only() {
+        let program = vec![
+            Instruction::Load(0, 0),
+            Instruction::JzReg(0, 0, 3),
+            Instruction::Load(1, 5),
+            Instruction::Out(1),
+            Instruction::Halt(),
+        ];
+        assert!(matches!(verify(&program), Err(VerifyError::UninitializedRegister { instruction: 3, register: 1 })));
+    }
+
+    #[test]
+    fn register_written_on_both_paths() {
+        let program = vec![
+            Instruction::Load(0, 0),
+            Instruction::JzReg(0, 0, 4),
+            Instruction::Load(1, 5),
+            Instruction::Jump16(0, 5),
+            Instruction::Load(1, 6),
+            Instruction::Out(1),
+            Instruction::Halt(),
+        ];
+        assert!(verify(&program).is_ok());
+    }
+}
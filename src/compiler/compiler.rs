@@ -1,109 +1,1126 @@
-use crate::compiler::node::{Node, ValueNode, Operator};
+use crate::compiler::node::{Node, ValueNode, Operator, UnaryOperator};
+use crate::compiler::optimizer::fold_constants;
 use crate::vm::instruction::Instruction;
 use crate::vm::machine::{REGISTERS, STACK_SIZE, IGNORE};
+use crate::diagnostic::Span;
 use std::collections::HashMap;
+use std::cmp::max;
+use std::fmt::{Debug, Formatter};
 
-fn compile_current(program: &mut Vec<Instruction>, registers: &mut [bool; REGISTERS], node: &Node, memory_map: &mut Vec<(usize, usize)>, variable_dictionary: &mut HashMap<String, (u8, u8)>) -> Vec<u8> {
+// Number of registers kept free for expression evaluation when storing variables in registers
+const RESERVED_REGISTERS: usize = 4;
+
+pub enum CompileStrategy {
+    FewestInstructions, // Keep variables in registers whenever they fit
+    FewestRegisters,    // Reuse operand registers for results and keep variables on the stack
+}
+
+pub struct CompilerOptions {
+    pub strategy: Option<CompileStrategy>, // None uses the default heuristics
+    pub register_variables: bool, // Keep variables in registers when enough are free, otherwise they are pushed to the stack
+    pub contiguous_variables: bool, // Reserve consecutive stack addresses for the top level variables before compiling
+    pub keep_results: bool, // Keep the register of a stack variable live for the next statement instead of reading it back from the stack
+    pub fold_constants: bool, // Compute the operations on constants before compiling
+}
+
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        return CompilerOptions {
+            strategy: None,
+            register_variables: false,
+            contiguous_variables: false,
+            keep_results: false,
+            fold_constants: true,
+        }
+    }
+}
+
+pub struct Intrinsic {
+    pub name: &'static str,
+    pub arguments: usize,   // Number of arguments
+    pub result: bool,       // Whether the intrinsic produces a value
+    pub lower: fn(&mut Vec<Instruction>, u8, &[u8]), // Emit the instructions from the result register (IGNORE if no result) and the argument registers
+}
+
+pub const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic { name: "min", arguments: 2, result: true, lower: |program, result, args| program.push(Instruction::Min(result, args[0], args[1])) },
+    Intrinsic { name: "max", arguments: 2, result: true, lower: |program, result, args| program.push(Instruction::Max(result, args[0], args[1])) },
+    Intrinsic { name: "out", arguments: 1, result: false, lower: |program, _, args| program.push(Instruction::Out(args[0])) },
+];
+
+pub enum CompileError {
+    OutOfRegisters,
+    DuplicateVariable(String),
+    UndefinedVariable(String),
+    UnknownIntrinsic(String),   // Unknown name or wrong number of arguments
+    InvalidOperand(String),     // Expression giving no value or a 16 bits value where a byte is needed
+    UnsupportedOperation(String),
+}
+
+impl Debug for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::OutOfRegisters => write!(f, "Out Of Registers: the expression needs more than {} registers", REGISTERS)?,
+            CompileError::DuplicateVariable(name) => write!(f, "Duplicate Variable: '{}' is already defined", name)?,
+            CompileError::UndefinedVariable(name) => write!(f, "Undefined Variable: '{}' is not defined", name)?,
+            CompileError::UnknownIntrinsic(name) => write!(f, "Unknown Intrinsic: '{}' doesn't exist or has the wrong number of arguments", name)?,
+            CompileError::InvalidOperand(node) => write!(f, "Invalid Operand: '{}' doesn't give a value of the needed size", node)?,
+            CompileError::UnsupportedOperation(operation) => write!(f, "Unsupported Operation: {}", operation)?,
+        }
+        Ok(())
+    }
+}
+
+pub enum Variable {
+    Stack(u8, u8),      // Address of the variable on the stack
+    Register(u8),       // Register holding the variable
+    Reserved(u8, u8),   // Address reserved on the stack for a variable that isn't defined yet
+    Cached(u8, u8, u8), // Variable on the stack at [arg0][arg1] whose value is still in the register [arg2]
+    Wide(u8, u8, u8, u8), // 16 bits variable, the high byte is on the stack at [arg0][arg1] and the low byte at [arg2][arg3]
+    ReservedWide(u8, u8, u8, u8), // Addresses reserved on the stack for a 16 bits variable that isn't defined yet
+}
+
+// Values pushed to the stack when every register was taken
+struct Spills {
+    slots: Vec<(u8, u8, u8)>,       // Register and stack address [arg1][arg2] of each spilled value, latest last
+    last_use: [usize; REGISTERS],   // Time each register was last allocated, the oldest one is spilled first
+    time: usize,
+    blocked: usize,                 // Number of If/While bodies being compiled, a SPush in them would shift the stack addresses
+}
+
+impl Spills {
+
+    fn new() -> Spills {
+        return Spills {
+            slots: vec![],
+            last_use: [0; REGISTERS],
+            time: 0,
+            blocked: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.slots.clear();
+        self.last_use.fill(0);
+        self.time = 0;
+        self.blocked = 0;
+    }
+
+}
+
+fn is_variable_register(variable_dictionary: &HashMap<String, Variable>, reg: u8) -> bool {
+    return variable_dictionary.values().any(|variable| match variable {
+        Variable::Register(var_reg) | Variable::Cached(_, _, var_reg) => *var_reg == reg,
+        _ => false,
+    });
+}
+
+// Take the first available stack address, same as SPush does at runtime
+fn allocate_memory(memory_map: &mut Vec<(usize, usize)>) -> (u8, u8) {
+    let map = memory_map.get_mut(0).unwrap();
+    let addr1 = ((map.0 >> 8) & 0xFF) as u8;
+    let addr2 = (map.0 & 0xFF) as u8;
+    if map.1 > 1 {
+        map.1 -= 1;
+        map.0 += 1;
+    } else {
+        memory_map.remove(0);
+    }
+    return (addr1, addr2);
+}
+
+// Number of registers the value of [node] is kept in once compiled
+fn value_width(node: &Node, variable_dictionary: &HashMap<String, Variable>) -> usize {
+    return match node {
+        Node::Value(ValueNode::U16(_)) => 2,
+        Node::BinOP(_, Operator::LESS, _) | Node::BinOP(_, Operator::EQUAL, _) | Node::BinOP(_, Operator::GREATER, _) => 1,
+        Node::BinOP(left, _, right) => max(value_width(left, variable_dictionary), value_width(right, variable_dictionary)),
+        Node::UnaryOp(_, value) => value_width(value, variable_dictionary),
+        Node::VariableCall(name) => match variable_dictionary.get(name) {
+            Some(Variable::Wide(_, _, _, _)) | Some(Variable::ReservedWide(_, _, _, _)) => 2,
+            _ => 1,
+        },
+        _ => 1,
+    }
+}
+
+// Definitions made by the statements of [body], including the ones of nested bodies
+fn defined_names<'a>(body: &'a [Node], names: &mut Vec<(&'a String, &'a Node)>) {
+    for node in body.iter() {
+        match node {
+            Node::VariableDefinition(name, value) if !names.iter().any(|(defined, _)| *defined == name) => names.push((name, value)),
+            Node::If(_, then_body, else_body) => {
+                defined_names(then_body, names);
+                defined_names(else_body, names);
+            }
+            Node::While(_, body) => defined_names(body, names),
+            _ => {}
+        }
+    }
+}
+
+// Buffers used while compiling, kept between compilations by compile_many
+struct CompilerState {
+    program: Vec<Instruction>,
+    registers: [bool; REGISTERS],
+    memory_map: Vec<(usize, usize)>,
+    spills: Spills,
+    variable_dictionary: HashMap<String, Variable>,
+    statement_starts: Vec<usize>, // Address of the first instruction of each top level node
+}
+
+impl CompilerState {
+
+    fn new() -> CompilerState {
+        return CompilerState {
+            program: vec![],
+            registers: [true; REGISTERS],
+            memory_map: vec![(0, STACK_SIZE)],
+            spills: Spills::new(),
+            variable_dictionary: HashMap::new(),
+            statement_starts: vec![],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.program.clear();
+        self.registers.fill(true);
+        self.memory_map.clear();
+        self.memory_map.push((0, STACK_SIZE));
+        self.spills.reset();
+        self.variable_dictionary.clear();
+        self.statement_starts.clear();
+    }
+
+}
+
+// Give stack addresses to the variables of [names] that aren't defined yet, filled with 0, 2 bytes for a 16 bits value
+// A SPush that doesn't run in a branch or runs again in a loop would shift the addresses of the variables pushed after it
+fn reserve_variables(state: &mut CompilerState, names: &[(&String, &Node)]) -> Result<(), CompileError> {
+    let new_names: Vec<&(&String, &Node)> = names.iter().filter(|(name, _)| !state.variable_dictionary.contains_key(*name)).collect();
+    if new_names.is_empty() {
+        return Ok(());
+    }
+    let zero = allocate_register(state, &[])?;
+    state.program.push(Instruction::Load(zero, 0));
+    for (name, value) in new_names {
+        let (addr1, addr2) = allocate_memory(&mut state.memory_map);
+        state.program.push(Instruction::SPush(IGNORE, IGNORE, zero));
+        // The width of a later value can depend on the variables reserved before it
+        if value_width(value, &state.variable_dictionary) == 2 {
+            let (low1, low2) = allocate_memory(&mut state.memory_map);
+            state.program.push(Instruction::SPush(IGNORE, IGNORE, zero));
+            state.variable_dictionary.insert((*name).clone(), Variable::ReservedWide(addr1, addr2, low1, low2));
+        } else {
+            state.variable_dictionary.insert((*name).clone(), Variable::Reserved(addr1, addr2));
+        }
+    }
+    state.registers[zero as usize] = true;
+    return Ok(());
+}
+
+fn jump_address(address: usize) -> (u8, u8) {
+    return (((address >> 8) & 0xFF) as u8, (address & 0xFF) as u8);
+}
+
+// Compile the statements of a body, the registers used by each statement are freed after it
+fn compile_body(state: &mut CompilerState, body: &[Node], options: &CompilerOptions) -> Result<(), CompileError> {
+    state.spills.blocked += 1;
+    for node in body.iter() {
+        let used_registers = compile_current(state, node, options)?;
+        for reg in used_registers {
+            if !is_variable_register(&state.variable_dictionary, reg) {
+                state.registers[reg as usize] = true;
+            }
+        }
+    }
+    state.spills.blocked -= 1;
+    return Ok(());
+}
+
+// Take a free register, if there is none the least recently used one that isn't in [keep] or holding a variable is spilled
+fn allocate_register(state: &mut CompilerState, keep: &[u8]) -> Result<u8, CompileError> {
+    state.spills.time += 1;
+    for i in 0..REGISTERS {
+        if state.registers[i] {
+            state.registers[i] = false;
+            state.spills.last_use[i] = state.spills.time;
+            return Ok(i as u8);
+        }
+    }
+    if state.spills.blocked > 0 {
+        return Err(CompileError::OutOfRegisters);
+    }
+    let victim = (0..REGISTERS as u8)
+        .filter(|reg| !keep.contains(reg) && !is_variable_register(&state.variable_dictionary, *reg))
+        .min_by_key(|reg| state.spills.last_use[*reg as usize]);
+    let victim = match victim {
+        Some(reg) => reg,
+        None => return Err(CompileError::OutOfRegisters),
+    };
+    let (addr1, addr2) = allocate_memory(&mut state.memory_map);
+    state.program.push(Instruction::SPush(IGNORE, IGNORE, victim));
+    state.spills.slots.push((victim, addr1, addr2));
+    state.spills.last_use[victim as usize] = state.spills.time;
+    return Ok(victim);
+}
+
+// Get back the value computed in [reg] when [since] values were spilled, it's in a new register if it was spilled meanwhile
+// The stack address of a reloaded value stays allocated, freeing it with SPop would make the next SPush take another address
+fn reload(state: &mut CompilerState, reg: u8, since: usize, keep: &[u8]) -> Result<u8, CompileError> {
+    let position = match state.spills.slots.iter().skip(since).position(|(spilled, _, _)| *spilled == reg) {
+        Some(position) => since + position,
+        None => return Ok(reg),
+    };
+    let (_, addr1, addr2) = state.spills.slots.remove(position);
+    let reg1 = allocate_register(state, keep)?;
+    let reg2 = allocate_register(state, &[keep, &[reg1]].concat())?;
+    state.program.push(Instruction::Load(reg1, addr1));
+    state.program.push(Instruction::Load(reg2, addr2));
+    state.program.push(Instruction::SCopy(reg1, reg2, reg1));
+    state.registers[reg2 as usize] = true;
+    return Ok(reg1);
+}
+
+// A 16 bits value is kept in 2 registers, the high byte first
+fn byte(used_registers: Vec<u8>, node: &Node) -> Result<u8, CompileError> {
+    if used_registers.len() != 1 {
+        return Err(CompileError::InvalidOperand(format!("{}", node)));
+    }
+    return Ok(used_registers[0]);
+}
+
+// Give a byte value a high byte of 0
+fn widen(state: &mut CompilerState, used_registers: Vec<u8>, node: &Node, keep: &[u8]) -> Result<[u8; 2], CompileError> {
+    return match used_registers.len() {
+        2 => Ok([used_registers[0], used_registers[1]]),
+        1 => {
+            let high = allocate_register(state, &[keep, &used_registers].concat())?;
+            state.program.push(Instruction::Load(high, 0));
+            Ok([high, used_registers[0]])
+        }
+        _ => Err(CompileError::InvalidOperand(format!("{}", node))),
+    }
+}
+
+// Register that is 0 only when the value is 0
+fn condition(state: &mut CompilerState, used_registers: Vec<u8>, node: &Node) -> Result<Vec<u8>, CompileError> {
+    if used_registers.len() != 2 {
+        return Ok(vec![byte(used_registers, node)?]);
+    }
+    let result = allocate_register(state, &used_registers)?;
+    state.program.push(Instruction::Or(result, used_registers[0], used_registers[1]));
+    for reg in used_registers {
+        if !is_variable_register(&state.variable_dictionary, reg) {
+            state.registers[reg as usize] = true;
+        }
+    }
+    return Ok(vec![result]);
+}
+
+// The bytes are added and subtracted separately (wrapping), the carry is found by comparing the low bytes
+fn compile_wide_operation(state: &mut CompilerState, op: &Operator, a: [u8; 2], b: [u8; 2]) -> Result<Vec<u8>, CompileError> {
+    if let Operator::DIVIDE | Operator::MODULO = op {
+        return Err(CompileError::UnsupportedOperation(format!("'{}' on 16 bits values", op)));
+    }
+    let [a_high, a_low] = a;
+    let [b_high, b_low] = b;
+    let mut used = vec![a_high, a_low, b_high, b_low];
+    for _ in 0..4 {
+        let reg = allocate_register(state, &used)?;
+        used.push(reg);
+    }
+    let (high, low, temp1, temp2) = (used[4], used[5], used[6], used[7]);
+    let result = match op {
+        Operator::PLUS => {
+            state.program.push(Instruction::Add(low, a_low, b_low));
+            state.program.push(Instruction::Cmp(temp1, low, a_low)); // 0 if the low bytes carried
+            state.program.push(Instruction::Add(high, a_high, b_high));
+            state.program.push(Instruction::Load(temp2, 1));
+            state.program.push(Instruction::Eq(temp1, 0));
+            state.program.push(Instruction::Add(high, high, temp2));
+            vec![high, low]
+        }
+        Operator::MINUS => {
+            state.program.push(Instruction::Cmp(temp1, a_low, b_low)); // 0 if the low bytes borrowed
+            state.program.push(Instruction::Sub(low, a_low, b_low));
+            state.program.push(Instruction::Sub(high, a_high, b_high));
+            state.program.push(Instruction::Load(temp2, 1));
+            state.program.push(Instruction::Eq(temp1, 0));
+            state.program.push(Instruction::Sub(high, high, temp2));
+            vec![high, low]
+        }
+        Operator::MULTIPLY => {
+            // The high bytes multiplied together only change bits above the 16 kept
+            state.program.push(Instruction::MulWide(high, low, a_low, b_low));
+            state.program.push(Instruction::Mul(temp1, a_high, b_low));
+            state.program.push(Instruction::Add(high, high, temp1));
+            state.program.push(Instruction::Mul(temp1, a_low, b_high));
+            state.program.push(Instruction::Add(high, high, temp1));
+            vec![high, low]
+        }
+        _ => {
+            // Order of the high bytes, or of the low bytes when they are equal
+            let order = match op {
+                Operator::LESS => 0,
+                Operator::EQUAL => 1,
+                _ => 2,
+            };
+            state.program.push(Instruction::Cmp(temp1, a_high, b_high));
+            state.program.push(Instruction::Cmp(temp2, a_low, b_low));
+            state.program.push(Instruction::Eq(temp1, 1));
+            state.program.push(Instruction::Mov(temp1, temp2));
+            state.program.push(Instruction::Load(high, 0));
+            state.program.push(Instruction::Eq(temp1, order));
+            state.program.push(Instruction::Load(high, 1));
+            vec![high]
+        }
+    };
+    for reg in used {
+        if !result.contains(&reg) && !is_variable_register(&state.variable_dictionary, reg) {
+            state.registers[reg as usize] = true;
+        }
+    }
+    return Ok(result);
+}
+
+fn compile_current(state: &mut CompilerState, node: &Node, options: &CompilerOptions) -> Result<Vec<u8>, CompileError> {
     match node {
         Node::Value(value_node) => {
             match value_node {
                 ValueNode::U8(value) => {
-                    for i in 0..REGISTERS {
-                        if registers[i] {
-                            registers[i] = false;
-                            program.push(Instruction::Load(i as u8, value.clone()));
-                            return vec![i as u8];
-                        }
-                    }
-                    panic!()
+                    let reg = allocate_register(state, &[])?;
+                    state.program.push(Instruction::Load(reg, value.clone()));
+                    return Ok(vec![reg]);
+                }
+                ValueNode::U16(value) => {
+                    let high = allocate_register(state, &[])?;
+                    let low = allocate_register(state, &[high])?;
+                    state.program.push(Instruction::Load(high, (value >> 8) as u8));
+                    state.program.push(Instruction::Load(low, (value & 0xFF) as u8));
+                    return Ok(vec![high, low]);
                 }
             }
         }
         Node::BinOP(left, op, right) => {
             let (used_register1, used_register2) = if left.get_weight() >= right.get_weight() {
-                let used_register1 = compile_current(program, registers, left, memory_map, variable_dictionary);
-                let used_register2 = compile_current(program, registers, right, memory_map, variable_dictionary);
+                let mut used_register1 = compile_current(state, left, options)?;
+                let since = state.spills.slots.len();
+                let used_register2 = compile_current(state, right, options)?;
+                for i in 0..used_register1.len() {
+                    let keep = [used_register1.clone(), used_register2.clone()].concat();
+                    used_register1[i] = reload(state, used_register1[i], since, &keep)?;
+                }
                 (used_register1, used_register2)
             } else {
-                let used_register2 = compile_current(program, registers, right, memory_map, variable_dictionary);
-                let used_register1 = compile_current(program, registers, left, memory_map, variable_dictionary);
+                let mut used_register2 = compile_current(state, right, options)?;
+                let since = state.spills.slots.len();
+                let used_register1 = compile_current(state, left, options)?;
+                for i in 0..used_register2.len() {
+                    let keep = [used_register1.clone(), used_register2.clone()].concat();
+                    used_register2[i] = reload(state, used_register2[i], since, &keep)?;
+                }
                 (used_register1, used_register2)
             };
-            for i in 0..REGISTERS {
-                if registers[i] {
-                    registers[i] = false;
-                    match op {
-                        Operator::PLUS => program.push(Instruction::Add(i as u8, used_register1[0], used_register2[0])),
-                        Operator::MINUS => program.push(Instruction::Sub(i as u8, used_register1[0], used_register2[0])),
-                        Operator::MULTIPLY => program.push(Instruction::Mul(i as u8, used_register1[0], used_register2[0])),
-                        Operator::DIVIDE => program.push(Instruction::Div(i as u8, used_register1[0], used_register2[0])),
+            // A byte operand is widened when the other one is a 16 bits value
+            if used_register1.len() == 2 || used_register2.len() == 2 {
+                let a = widen(state, used_register1, left, &used_register2)?;
+                let b = widen(state, used_register2, right, &a)?;
+                return compile_wide_operation(state, op, a, b);
+            }
+            let used_register1 = byte(used_register1, left)?;
+            let used_register2 = byte(used_register2, right)?;
+            // Write the result over one of the operands instead of taking a new register
+            let reused_register = match options.strategy {
+                Some(CompileStrategy::FewestRegisters) => [used_register1, used_register2].iter().copied()
+                    .find(|reg| !is_variable_register(&state.variable_dictionary, *reg)),
+                _ => None,
+            };
+            let result = match reused_register {
+                Some(reg) => reg,
+                None => allocate_register(state, &[used_register1, used_register2])?,
+            };
+            match op {
+                Operator::PLUS => state.program.push(Instruction::Add(result, used_register1, used_register2)),
+                Operator::MINUS => state.program.push(Instruction::Sub(result, used_register1, used_register2)),
+                Operator::MULTIPLY => state.program.push(Instruction::Mul(result, used_register1, used_register2)),
+                Operator::DIVIDE => state.program.push(Instruction::Div(result, used_register1, used_register2)),
+                Operator::MODULO => state.program.push(Instruction::Mod(result, used_register1, used_register2)),
+                _ => {
+                    // CMP gives 0 (<), 1 (==) or 2 (>), the result is 1 if it's [order] else 0
+                    let order = match op {
+                        Operator::LESS => 0,
+                        Operator::EQUAL => 1,
+                        _ => 2,
+                    };
+                    let reg_order = allocate_register(state, &[result, used_register1, used_register2])?;
+                    state.program.push(Instruction::Cmp(reg_order, used_register1, used_register2));
+                    state.program.push(Instruction::Load(result, 0));
+                    state.program.push(Instruction::Eq(reg_order, order));
+                    state.program.push(Instruction::Load(result, 1));
+                    state.registers[reg_order as usize] = true;
+                }
+            }
+            // Registers holding variables stay reserved
+            if used_register1 != result && !is_variable_register(&state.variable_dictionary, used_register1) {
+                state.registers[used_register1 as usize] = true;
+            }
+            if used_register2 != result && !is_variable_register(&state.variable_dictionary, used_register2) {
+                state.registers[used_register2 as usize] = true;
+            }
+            return Ok(vec![result]);
+        }
+        Node::UnaryOp(op, value) => {
+            let used_registers = compile_current(state, value, options)?;
+            match op {
+                UnaryOperator::NEGATE => {
+                    // 0 - value
+                    let zero = allocate_register(state, &used_registers)?;
+                    state.program.push(Instruction::Load(zero, 0));
+                    if used_registers.len() == 2 {
+                        let value = [used_registers[0], used_registers[1]];
+                        return compile_wide_operation(state, &Operator::MINUS, [zero, zero], value);
                     }
-                    registers[used_register1[0] as usize] = true;
-                    registers[used_register2[0] as usize] = true;
-                    return vec![i as u8];
+                    let value = byte(used_registers, value)?;
+                    state.program.push(Instruction::Sub(zero, zero, value));
+                    if !is_variable_register(&state.variable_dictionary, value) {
+                        state.registers[value as usize] = true;
+                    }
+                    return Ok(vec![zero]);
                 }
             }
-            panic!();
         }
         Node::VariableDefinition(name, value) => {
-            let value = compile_current(program, registers, value, memory_map, variable_dictionary);
-            if variable_dictionary.get(name).is_none() {
-                let map = memory_map.get_mut(0).unwrap();
-                let addr1 = ((map.0 >> 8) & 0xFF) as u8;
-                let addr2 = (map.0 & 0xFF) as u8;
-                if map.1 > 1 {
-                    map.1 -= 1;
-                    map.0 += 1;
-                } else {
-                    memory_map.remove(0);
-                }
-
-                variable_dictionary.insert(name.clone(), (addr1, addr2));
-                program.push(Instruction::SPush(IGNORE, IGNORE, value[0]));
-                return vec![];
-            } else {
-                panic!()
+            let used_registers = compile_current(state, value, options)?;
+            match state.variable_dictionary.get(name) {
+                None => {
+                    if used_registers.len() == 2 {
+                        // 16 bits variables are always on the stack
+                        let (high1, high2) = allocate_memory(&mut state.memory_map);
+                        state.program.push(Instruction::SPush(IGNORE, IGNORE, used_registers[0]));
+                        let (low1, low2) = allocate_memory(&mut state.memory_map);
+                        state.program.push(Instruction::SPush(IGNORE, IGNORE, used_registers[1]));
+                        state.variable_dictionary.insert(name.clone(), Variable::Wide(high1, high2, low1, low2));
+                        return Ok(vec![]);
+                    }
+                    let value = byte(used_registers, value)?;
+                    let register_variables = match options.strategy {
+                        Some(CompileStrategy::FewestInstructions) => true,
+                        Some(CompileStrategy::FewestRegisters) => false,
+                        None => options.register_variables,
+                    };
+                    if register_variables && state.registers.iter().filter(|free| **free).count() >= RESERVED_REGISTERS {
+                        // The register holding the value is kept for the variable, a copy is made if it already belongs to another one
+                        let reg = if is_variable_register(&state.variable_dictionary, value) {
+                            let reg = allocate_register(state, &[value])?;
+                            state.program.push(Instruction::Mov(reg, value));
+                            reg
+                        } else {
+                            value
+                        };
+                        state.variable_dictionary.insert(name.clone(), Variable::Register(reg));
+                        return Ok(vec![]);
+                    }
+
+                    let (addr1, addr2) = allocate_memory(&mut state.memory_map);
+                    if options.keep_results {
+                        state.variable_dictionary.insert(name.clone(), Variable::Cached(addr1, addr2, value));
+                    } else {
+                        state.variable_dictionary.insert(name.clone(), Variable::Stack(addr1, addr2));
+                    }
+                    state.program.push(Instruction::SPush(IGNORE, IGNORE, value));
+                    return Ok(vec![]);
+                }
+                Some(Variable::Reserved(addr1, addr2)) => {
+                    // Only a byte is reserved
+                    let (addr1, addr2) = (*addr1, *addr2);
+                    let value = byte(used_registers, value)?;
+                    let reg1 = allocate_register(state, &[value])?;
+                    let reg2 = allocate_register(state, &[value, reg1])?;
+                    state.program.push(Instruction::Load(reg1, addr1));
+                    state.program.push(Instruction::Load(reg2, addr2));
+                    state.program.push(Instruction::SRep(reg1, reg2, value));
+                    state.registers[reg1 as usize] = true;
+                    state.registers[reg2 as usize] = true;
+                    state.variable_dictionary.insert(name.clone(), Variable::Stack(addr1, addr2));
+                    return Ok(vec![]);
+                }
+                Some(Variable::ReservedWide(high1, high2, low1, low2)) => {
+                    let addresses = [(*high1, *high2), (*low1, *low2)];
+                    let value = widen(state, used_registers, value, &[])?;
+                    let reg1 = allocate_register(state, &value)?;
+                    let reg2 = allocate_register(state, &[value[0], value[1], reg1])?;
+                    for (reg, (addr1, addr2)) in value.iter().zip(addresses.iter()) {
+                        state.program.push(Instruction::Load(reg1, *addr1));
+                        state.program.push(Instruction::Load(reg2, *addr2));
+                        state.program.push(Instruction::SRep(reg1, reg2, *reg));
+                    }
+                    for reg in [reg1, reg2, value[0], value[1]] {
+                        if !is_variable_register(&state.variable_dictionary, reg) {
+                            state.registers[reg as usize] = true;
+                        }
+                    }
+                    let [(high1, high2), (low1, low2)] = addresses;
+                    state.variable_dictionary.insert(name.clone(), Variable::Wide(high1, high2, low1, low2));
+                    return Ok(vec![]);
+                }
+                Some(_) => return Err(CompileError::DuplicateVariable(name.clone())),
             }
         }
+        Node::VariableAssignment(name, value) => {
+            let used_registers = compile_current(state, value, options)?;
+            if let Some(Variable::Wide(high1, high2, low1, low2)) = state.variable_dictionary.get(name) {
+                let addresses = [(*high1, *high2), (*low1, *low2)];
+                let value = widen(state, used_registers, value, &[])?;
+                let reg1 = allocate_register(state, &value)?;
+                let reg2 = allocate_register(state, &[value[0], value[1], reg1])?;
+                for (reg, (addr1, addr2)) in value.iter().zip(addresses.iter()) {
+                    state.program.push(Instruction::Load(reg1, *addr1));
+                    state.program.push(Instruction::Load(reg2, *addr2));
+                    state.program.push(Instruction::SRep(reg1, reg2, *reg));
+                }
+                for reg in [reg1, reg2, value[0], value[1]] {
+                    if !is_variable_register(&state.variable_dictionary, reg) {
+                        state.registers[reg as usize] = true;
+                    }
+                }
+                return Ok(vec![]);
+            }
+            let value = byte(used_registers, value)?;
+            match state.variable_dictionary.get(name) {
+                Some(Variable::Register(reg)) => state.program.push(Instruction::Mov(*reg, value)),
+                Some(Variable::Stack(addr1, addr2)) | Some(Variable::Cached(addr1, addr2, _)) => {
+                    let (addr1, addr2) = (*addr1, *addr2);
+                    // The register of a cached variable holds the old value
+                    if let Some(Variable::Cached(_, _, reg)) = state.variable_dictionary.get(name) {
+                        state.registers[*reg as usize] = true;
+                        state.variable_dictionary.insert(name.clone(), Variable::Stack(addr1, addr2));
+                    }
+                    let reg1 = allocate_register(state, &[value])?;
+                    let reg2 = allocate_register(state, &[value, reg1])?;
+                    state.program.push(Instruction::Load(reg1, addr1));
+                    state.program.push(Instruction::Load(reg2, addr2));
+                    state.program.push(Instruction::SRep(reg1, reg2, value));
+                    state.registers[reg1 as usize] = true;
+                    state.registers[reg2 as usize] = true;
+                }
+                Some(Variable::Wide(_, _, _, _)) | Some(Variable::Reserved(_, _)) | Some(Variable::ReservedWide(_, _, _, _)) | None => return Err(CompileError::UndefinedVariable(name.clone())),
+            }
+            if !is_variable_register(&state.variable_dictionary, value) {
+                state.registers[value as usize] = true;
+            }
+            return Ok(vec![]);
+        }
         Node::VariableCall(name) => {
-            if let Some(var) = variable_dictionary.get(name) {
-                let mut reg1: Option<u8> = None;
-                let mut reg2: Option<u8> = None;
-                let mut reg3: Option<u8> = None;
-                for i in 0..REGISTERS {
-                    if registers[i] {
-                        registers[i] = false;
-                        if reg1.is_none() {
-                            reg1 = Some(i as u8);
-                            program.push(Instruction::Load(i as u8, var.0));
-                        } else if reg2.is_none() {
-                            reg2 = Some(i as u8);
-                            program.push(Instruction::Load(i as u8, var.1));
-                        } else {
-                            reg3 = Some(i as u8);
-                            program.push(Instruction::SCopy(reg1.unwrap(), reg2.unwrap(), reg3.unwrap()));
-                            return vec![reg3.unwrap(), reg1.unwrap(), reg2.unwrap()];
-                        }
+            match state.variable_dictionary.get(name) {
+                Some(Variable::Register(reg)) | Some(Variable::Cached(_, _, reg)) => return Ok(vec![*reg]),
+                Some(Variable::Stack(addr1, addr2)) => {
+                    if let Some(CompileStrategy::FewestRegisters) = options.strategy {
+                        // Read the value over the first address register
+                        let (addr1, addr2) = (*addr1, *addr2);
+                        let reg1 = allocate_register(state, &[])?;
+                        let reg2 = allocate_register(state, &[reg1])?;
+                        state.program.push(Instruction::Load(reg1, addr1));
+                        state.program.push(Instruction::Load(reg2, addr2));
+                        state.program.push(Instruction::SCopy(reg1, reg2, reg1));
+                        state.registers[reg2 as usize] = true;
+                        return Ok(vec![reg1]);
                     }
+
+                    let (addr1, addr2) = (*addr1, *addr2);
+                    let reg1 = allocate_register(state, &[])?;
+                    state.program.push(Instruction::Load(reg1, addr1));
+                    let reg2 = allocate_register(state, &[reg1])?;
+                    state.program.push(Instruction::Load(reg2, addr2));
+                    let reg3 = allocate_register(state, &[reg1, reg2])?;
+                    state.program.push(Instruction::SCopy(reg1, reg2, reg3));
+                    state.registers[reg1 as usize] = true;
+                    state.registers[reg2 as usize] = true;
+                    return Ok(vec![reg3]);
+                }
+                Some(Variable::Wide(high1, high2, low1, low2)) => {
+                    let addresses = [(*high1, *high2), (*low1, *low2)];
+                    let reg1 = allocate_register(state, &[])?;
+                    let reg2 = allocate_register(state, &[reg1])?;
+                    let high = allocate_register(state, &[reg1, reg2])?;
+                    let low = allocate_register(state, &[reg1, reg2, high])?;
+                    for (reg, (addr1, addr2)) in [high, low].iter().zip(addresses.iter()) {
+                        state.program.push(Instruction::Load(reg1, *addr1));
+                        state.program.push(Instruction::Load(reg2, *addr2));
+                        state.program.push(Instruction::SCopy(reg1, reg2, *reg));
+                    }
+                    state.registers[reg1 as usize] = true;
+                    state.registers[reg2 as usize] = true;
+                    return Ok(vec![high, low]);
+                }
+                Some(Variable::Reserved(_, _)) | Some(Variable::ReservedWide(_, _, _, _)) | None => return Err(CompileError::UndefinedVariable(name.clone())),
+            }
+        }
+        Node::Ternary(cond, a, b) => {
+            let reg_cond = byte(compile_current(state, cond, options)?, cond)?;
+            let since_cond = state.spills.slots.len();
+            let reg_a = byte(compile_current(state, a, options)?, a)?;
+            let since_a = state.spills.slots.len();
+            let reg_b = byte(compile_current(state, b, options)?, b)?;
+            let reg_a = reload(state, reg_a, since_a, &[reg_b])?;
+            let reg_cond = reload(state, reg_cond, since_cond, &[reg_a, reg_b])?;
+            // Start from [b] and replace it by [a] when the condition is true
+            let result = if is_variable_register(&state.variable_dictionary, reg_b) {
+                let result = allocate_register(state, &[reg_cond, reg_a, reg_b])?;
+                state.program.push(Instruction::Mov(result, reg_b));
+                result
+            } else {
+                reg_b
+            };
+            state.program.push(Instruction::CMov(result, reg_a, reg_cond));
+            for reg in [reg_cond, reg_a] {
+                if reg != result && !is_variable_register(&state.variable_dictionary, reg) {
+                    state.registers[reg as usize] = true;
+                }
+            }
+            return Ok(vec![result]);
+        }
+        Node::If(cond, then_body, else_body) => {
+            // Variables defined in the branches get their stack address before the condition
+            let mut definitions = vec![];
+            defined_names(then_body, &mut definitions);
+            defined_names(else_body, &mut definitions);
+            reserve_variables(state, &definitions)?;
+            let names: Vec<String> = definitions.iter()
+                .filter(|(name, _)| matches!(state.variable_dictionary.get(*name), Some(Variable::Reserved(_, _)) | Some(Variable::ReservedWide(_, _, _, _))))
+                .map(|(name, _)| (*name).clone())
+                .collect();
+
+            let cond_registers = compile_current(state, cond, options)?;
+            let cond_registers = condition(state, cond_registers, cond)?;
+            let reg_cond = cond_registers[0];
+            let skip_then = state.program.len();
+            state.program.push(Instruction::JzReg(reg_cond, 0, 0)); // Address set once the then body is compiled
+            for reg in cond_registers {
+                if !is_variable_register(&state.variable_dictionary, reg) {
+                    state.registers[reg as usize] = true;
+                }
+            }
+
+            compile_body(state, then_body, options)?;
+            // The else body can define the same variables again
+            let mut defined = vec![];
+            for name in names.iter() {
+                let reserved = match state.variable_dictionary.get(name) {
+                    Some(Variable::Stack(addr1, addr2)) => Variable::Reserved(*addr1, *addr2),
+                    Some(Variable::Wide(high1, high2, low1, low2)) => Variable::ReservedWide(*high1, *high2, *low1, *low2),
+                    _ => continue,
+                };
+                state.variable_dictionary.insert(name.clone(), reserved);
+                defined.push(name.clone());
+            }
+
+            if else_body.is_empty() {
+                let (addr1, addr2) = jump_address(state.program.len());
+                state.program[skip_then] = Instruction::JzReg(reg_cond, addr1, addr2);
+            } else {
+                let skip_else = state.program.len();
+                state.program.push(Instruction::Jump16(0, 0));
+                let (addr1, addr2) = jump_address(state.program.len());
+                state.program[skip_then] = Instruction::JzReg(reg_cond, addr1, addr2);
+                compile_body(state, else_body, options)?;
+                let (addr1, addr2) = jump_address(state.program.len());
+                state.program[skip_else] = Instruction::Jump16(addr1, addr2);
+            }
+
+            // A variable defined by one branch keeps 0 when the other one runs
+            for name in defined {
+                let variable = match state.variable_dictionary.get(&name) {
+                    Some(Variable::Reserved(addr1, addr2)) => Variable::Stack(*addr1, *addr2),
+                    Some(Variable::ReservedWide(high1, high2, low1, low2)) => Variable::Wide(*high1, *high2, *low1, *low2),
+                    _ => continue,
+                };
+                state.variable_dictionary.insert(name, variable);
+            }
+            return Ok(vec![]);
+        }
+        Node::While(cond, body) => {
+            let mut names = vec![];
+            defined_names(body, &mut names);
+            reserve_variables(state, &names)?;
+            // Registers of cached variables can be overwritten by the body before the condition runs again
+            let cached: Vec<String> = state.variable_dictionary.iter()
+                .filter(|(_, variable)| matches!(variable, Variable::Cached(_, _, _)))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in cached {
+                if let Some(Variable::Cached(addr1, addr2, reg)) = state.variable_dictionary.get(&name) {
+                    let (addr1, addr2, reg) = (*addr1, *addr2, *reg);
+                    state.variable_dictionary.insert(name, Variable::Stack(addr1, addr2));
+                    state.registers[reg as usize] = true;
+                }
+            }
+
+            let start = state.program.len();
+            state.spills.blocked += 1; // The condition runs again after the body
+            let cond_registers = compile_current(state, cond, options)?;
+            let cond_registers = condition(state, cond_registers, cond)?;
+            let reg_cond = cond_registers[0];
+            let exit = state.program.len();
+            state.program.push(Instruction::JzReg(reg_cond, 0, 0)); // Address set once the body is compiled
+            for reg in cond_registers {
+                if !is_variable_register(&state.variable_dictionary, reg) {
+                    state.registers[reg as usize] = true;
+                }
+            }
+            state.spills.blocked -= 1;
+
+            compile_body(state, body, options)?;
+            let (addr1, addr2) = jump_address(start);
+            state.program.push(Instruction::Jump16(addr1, addr2));
+            let (addr1, addr2) = jump_address(state.program.len());
+            state.program[exit] = Instruction::JzReg(reg_cond, addr1, addr2);
+            return Ok(vec![]);
+        }
+        Node::Print(value) => {
+            let used_registers = compile_current(state, value, options)?;
+            if used_registers.is_empty() {
+                return Err(CompileError::InvalidOperand(format!("{}", value)));
+            }
+            for reg in used_registers {
+                state.program.push(Instruction::Out(reg));
+                if !is_variable_register(&state.variable_dictionary, reg) {
+                    state.registers[reg as usize] = true;
+                }
+            }
+            return Ok(vec![]);
+        }
+        Node::Intrinsic(name, arguments) => {
+            // Unknown intrinsic or wrong number of arguments
+            let intrinsic = match INTRINSICS.iter().find(|intrinsic| intrinsic.name == name) {
+                Some(intrinsic) if intrinsic.arguments == arguments.len() => intrinsic,
+                _ => return Err(CompileError::UnknownIntrinsic(name.clone())),
+            };
+            let mut argument_registers = vec![];
+            let mut since = vec![];
+            for argument in arguments.iter() {
+                argument_registers.push(byte(compile_current(state, argument, options)?, argument)?);
+                since.push(state.spills.slots.len());
+            }
+            for i in (0..argument_registers.len()).rev() {
+                let keep: Vec<u8> = argument_registers.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, reg)| *reg).collect();
+                argument_registers[i] = reload(state, argument_registers[i], since[i], &keep)?;
+            }
+            let result = if intrinsic.result {
+                allocate_register(state, &argument_registers)?
+            } else {
+                IGNORE
+            };
+            (intrinsic.lower)(&mut state.program, result, &argument_registers);
+            for reg in argument_registers {
+                if !is_variable_register(&state.variable_dictionary, reg) {
+                    state.registers[reg as usize] = true;
                 }
-                panic!()
+            }
+            return if intrinsic.result {
+                Ok(vec![result])
             } else {
-                panic!()
+                Ok(vec![])
             }
         }
     }
 }
 
-pub fn compile(ast: Vec<Node>) -> Vec<Instruction> {
-    let mut program = vec![];
-    let mut registers = [true; REGISTERS];
-    let mut memory_map: Vec<(usize, usize)> = vec![(0, STACK_SIZE)];
-    let mut variable_dictionary: HashMap<String, (u8, u8)> = HashMap::new();
+fn compile_ast(ast: &[Node], options: &CompilerOptions, state: &mut CompilerState) -> Result<Vec<Instruction>, CompileError> {
+    state.reset();
+
+    if options.contiguous_variables {
+        // Push zeros for each top level variable so they get consecutive addresses, definitions then replace them
+        let mut names = vec![];
+        for node in ast.iter() {
+            if let Node::VariableDefinition(name, value) = node {
+                if !names.iter().any(|(defined, _)| *defined == name) {
+                    names.push((name, value.as_ref()));
+                }
+            }
+        }
+        reserve_variables(state, &names)?;
+    }
 
     for node in ast.iter() {
-        compile_current(&mut program, &mut registers, node, &mut memory_map, &mut variable_dictionary);
-        registers.fill(true); // Free All registers
+        // Values kept from the previous statement are only valid during this one
+        let cached: Vec<String> = state.variable_dictionary.iter()
+            .filter(|(_, variable)| matches!(variable, Variable::Cached(_, _, _)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        state.statement_starts.push(state.program.len());
+        compile_current(state, node, options)?;
+        state.spills.slots.clear(); // Values still spilled aren't used by the next statements
+        for name in cached {
+            if let Some(Variable::Cached(addr1, addr2, _)) = state.variable_dictionary.get(&name) {
+                let (addr1, addr2) = (*addr1, *addr2);
+                state.variable_dictionary.insert(name, Variable::Stack(addr1, addr2));
+            }
+        }
+        state.registers.fill(true); // Free All registers
+        for variable in state.variable_dictionary.values() {
+            if let Variable::Register(reg) | Variable::Cached(_, _, reg) = variable {
+                state.registers[*reg as usize] = false; // Except the ones holding variables
+            }
+        }
+    }
+
+    return Ok(std::mem::take(&mut state.program));
+}
+
+pub fn compile(ast: Vec<Node>) -> Result<Vec<Instruction>, CompileError> {
+    return compile_with_options(ast, CompilerOptions::default());
+}
+
+pub fn compile_with_options(ast: Vec<Node>, options: CompilerOptions) -> Result<Vec<Instruction>, CompileError> {
+    let ast: Vec<Node> = if options.fold_constants {
+        ast.into_iter().map(fold_constants).collect()
+    } else {
+        ast
+    };
+    return compile_ast(&ast, &options, &mut CompilerState::new());
+}
+
+// Same as compile_with_options, each instruction also gets the span of the top level node it comes from (parse_with_spans)
+// The variables reserved before the first node get its span, instructions of nodes without a span get none
+pub fn compile_with_spans(ast: Vec<Node>, spans: &[Span], options: CompilerOptions) -> Result<(Vec<Instruction>, Vec<Span>), CompileError> {
+    let ast: Vec<Node> = if options.fold_constants {
+        ast.into_iter().map(fold_constants).collect()
+    } else {
+        ast
+    };
+    let mut state = CompilerState::new();
+    let program = compile_ast(&ast, &options, &mut state)?;
+    let mut instruction_spans = vec![];
+    for (i, span) in spans.iter().enumerate().take(state.statement_starts.len()) {
+        let end = state.statement_starts.get(i + 1).copied().unwrap_or(program.len());
+        instruction_spans.resize(end, *span);
+    }
+    return Ok((program, instruction_spans));
+}
+
+// Compile each AST independently, the compiler buffers are reused between them
+// An AST that fails to compile doesn't stop the others
+pub fn compile_many(asts: Vec<Vec<Node>>, options: CompilerOptions) -> Vec<Result<Vec<Instruction>, CompileError>> {
+    let mut state = CompilerState::new();
+    return asts.into_iter()
+        .map(|ast| if options.fold_constants {
+            ast.into_iter().map(fold_constants).collect::<Vec<Node>>()
+        } else {
+            ast
+        })
+        .map(|ast| compile_ast(&ast, &options, &mut state))
+        .collect();
+}
+
+// Compile to assembly text, the instructions of each top level node are preceded by a comment with its source
+pub fn compile_to_asm(ast: Vec<Node>) -> Result<String, CompileError> {
+    let ast: Vec<Node> = ast.into_iter().map(fold_constants).collect();
+    let mut state = CompilerState::new();
+    let program = compile_ast(&ast, &CompilerOptions::default(), &mut state)?;
+    let mut result = String::new();
+    for (address, instruction) in program.iter().enumerate() {
+        for (node, start) in ast.iter().zip(state.statement_starts.iter()) {
+            if *start == address {
+                result.push_str(&format!("# {}\n", node));
+            }
+        }
+        result.push_str(&format!("{:?}\n", instruction));
+    }
+    return Ok(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parser::parse;
+    use crate::vm::machine::{VM, HaltReason, register_operands};
+    use crate::vm::machine::tests::Capture;
+
+    fn register_variables() -> CompilerOptions {
+        return CompilerOptions { register_variables: true, ..CompilerOptions::default() };
+    }
+
+    fn strategy(strategy: CompileStrategy) -> CompilerOptions {
+        return CompilerOptions { strategy: Some(strategy), ..CompilerOptions::default() };
+    }
+
+    // Every way of compiling a program, they all have to give the same output
+    fn all_options() -> Vec<CompilerOptions> {
+        return vec![
+            CompilerOptions::default(),
+            register_variables(),
+            strategy(CompileStrategy::FewestInstructions),
+            strategy(CompileStrategy::FewestRegisters),
+        ];
+    }
+
+    // Number of distinct registers used by the program
+    fn register_count(program: &[Instruction]) -> usize {
+        let mut registers: Vec<u8> = program.iter().flat_map(register_operands).collect();
+        registers.sort();
+        registers.dedup();
+        return registers.len();
+    }
+
+    // Bytes written by OUT when the compiled source runs
+    fn output(source: &str, options: CompilerOptions) -> Vec<u8> {
+        let program = compile_with_options(parse(source).unwrap(), options).unwrap();
+        let mut vm = VM::new(program);
+        let capture = Capture::new();
+        vm.set_output(Box::new(capture.clone()));
+        vm.run();
+        return capture.bytes();
+    }
+
+    // VM after running the AST, with the bytes written by OUT
+    fn run_ast(ast: Vec<Node>, options: CompilerOptions) -> (VM, Vec<u8>) {
+        let mut vm = VM::new(compile_with_options(ast, options).unwrap());
+        let capture = Capture::new();
+        vm.set_output(Box::new(capture.clone()));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        return (vm, capture.bytes());
+    }
+
+    #[test]
+    fn register_variables_avoid_the_stack() {
+        let program = compile_with_options(parse("let a = 4; let b = 5; a + b").unwrap(), register_variables()).unwrap();
+        assert!(!program.iter().any(|instruction| matches!(instruction, Instruction::SPush(_, _, _))));
     }
 
-    return program;
-}
\ No newline at end of file
+    #[test]
+    fn register_variable_copied_from_another_one_gets_its_own_register() {
+        let source = "let a = 1; let b = a; a = 2; print(b); print(a)";
+        assert_eq!(output(source, register_variables()), vec![1, 2]);
+        assert_eq!(output(source, CompilerOptions::default()), vec![1, 2]);
+    }
+
+    #[test]
+    fn strategies_give_the_same_output() {
+        let sources = [
+            "let a = 1; let b = a; a = 2; print(b); print(a)",
+            "let a = 4; let b = 5; let c = a * b + a; print(c - b)",
+            "let a = 300; let b = a + 20; print(b); print(-a)",
+            "let a = 7; a = a * a - 1; print(a / 3)",
+        ];
+        for source in sources.iter() {
+            let expected = output(source, CompilerOptions::default());
+            for options in all_options() {
+                assert_eq!(output(source, options), expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn strategies_trade_instructions_for_registers() {
+        let ast = || parse("let a = 4; let b = 5; let c = a * b + a; print(c - b)").unwrap();
+        let default = compile(ast()).unwrap();
+        let fewest_instructions = compile_with_options(ast(), strategy(CompileStrategy::FewestInstructions)).unwrap();
+        let fewest_registers = compile_with_options(ast(), strategy(CompileStrategy::FewestRegisters)).unwrap();
+        assert!(fewest_instructions.len() < default.len());
+        assert!(register_count(&fewest_registers) < register_count(&default));
+    }
+
+    const SOURCES: [&str; 5] = [
+        "let a = 4 + 5 * 2; a * 3",
+        "let a = 1; print(b)",
+        "let a = 300; let b = a * 2; print(b)",
+        "let a = 7; let a = 8",
+        "let x = 9; x = -x; print(x)",
+    ];
+
+    #[test]
+    fn compile_many_matches_compile() {
+        let options = || {
+            let mut options = all_options();
+            options.push(CompilerOptions { fold_constants: false, ..CompilerOptions::default() });
+            return options;
+        };
+        for i in 0..options().len() {
+            let batch = compile_many(SOURCES.iter().map(|source| parse(source).unwrap()).collect(), options().remove(i));
+            assert_eq!(batch.len(), SOURCES.len());
+            for (source, result) in SOURCES.iter().zip(batch.into_iter()) {
+                match (compile_with_options(parse(source).unwrap(), options().remove(i)), result) {
+                    (Ok(expected), Ok(program)) => assert_eq!(program, expected, "{}", source),
+                    (Err(expected), Err(err)) => assert_eq!(format!("{:?}", err), format!("{:?}", expected), "{}", source),
+                    (expected, result) => panic!("{}: {:?} != {:?}", source, result.map(|_| ()), expected.map(|_| ())),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn contiguous_variables_fit_their_value() {
+        let contiguous = || CompilerOptions { contiguous_variables: true, ..CompilerOptions::default() };
+        let source = "let a = 300; let b = a + 2; let c = 7; print(a); print(b); print(c)";
+        assert_eq!(output(source, contiguous()), vec![1, 44, 1, 46, 7]);
+        assert_eq!(output(source, contiguous()), output(source, CompilerOptions::default()));
+        // 2 bytes for a and b, 1 for c, pushed before the first definition
+        let program = compile_with_options(parse(source).unwrap(), contiguous()).unwrap();
+        assert!(program[1..6].iter().all(|instruction| matches!(instruction, Instruction::SPush(IGNORE, IGNORE, _))));
+        assert!(!matches!(program[6], Instruction::SPush(_, _, _)));
+    }
+
+    #[test]
+    fn if_runs_the_branch_of_its_condition() {
+        // if a { print(1); b = 10 } else { print(2); b = 20 }; print(b)
+        for (a, expected) in [(1, vec![1, 10]), (0, vec![2, 20]), (7, vec![1, 10])].iter() {
+            for options in all_options() {
+                let mut ast = parse(&format!("let a = {}; let b = 0", a)).unwrap();
+                ast.push(Node::If(Box::new(Node::VariableCall("a".to_string())), parse("print(1); b = 10").unwrap(), parse("print(2); b = 20").unwrap()));
+                ast.extend(parse("print(b)").unwrap());
+                assert_eq!(run_ast(ast, options).1, *expected, "a = {}", a);
+            }
+        }
+        // Without else the not taken branch does nothing
+        let mut ast = parse("let a = 0").unwrap();
+        ast.push(Node::If(Box::new(Node::VariableCall("a".to_string())), parse("print(1)").unwrap(), vec![]));
+        ast.extend(parse("print(3)").unwrap());
+        assert_eq!(run_ast(ast, CompilerOptions::default()).1, vec![3]);
+    }
+
+    #[test]
+    fn while_counts_down() {
+        let countdown = || {
+            let mut ast = parse("let i = 3; let n = 0").unwrap();
+            ast.push(Node::While(Box::new(Node::VariableCall("i".to_string())), parse("n = n + 2; i = i - 1").unwrap()));
+            ast.extend(parse("print(n); print(i)").unwrap());
+            return ast;
+        };
+        for options in all_options() {
+            assert_eq!(run_ast(countdown(), options).1, vec![6, 0]);
+        }
+        // i and n are pushed in order on the stack
+        let (vm, _) = run_ast(countdown(), CompilerOptions::default());
+        assert_eq!((vm.memory(0), vm.memory(1)), (Some(0), Some(6)));
+        assert!(vm.is_allocated(0) && vm.is_allocated(1) && !vm.is_allocated(2));
+        // The body runs once per count and the condition once more
+        assert_eq!(vm.opcode_counts().get("JZR"), Some(&4));
+    }
+
+    #[test]
+    fn temporaries_past_the_registers_are_spilled() {
+        // (1 + 1) + ((2 + 2) + (... + (20 + 20))): the left sum of each level stays live while the right one is computed
+        let mut expression = "(20 + 20)".to_string();
+        for i in (1..20).rev() {
+            expression = format!("({} + {}) + ({})", i, i, expression);
+        }
+        let source = format!("print({})", expression);
+        let unfolded = || CompilerOptions { fold_constants: false, ..CompilerOptions::default() };
+        let program = compile_with_options(parse(&source).unwrap(), unfolded()).unwrap();
+        assert!(program.iter().any(|instruction| matches!(instruction, Instruction::SPush(_, _, _))));
+        // 2 * (1 + ... + 20) = 420, wrapped to a byte
+        assert_eq!(run_ast(parse(&source).unwrap(), unfolded()).1, vec![(420 % 256) as u8]);
+    }
+
+    #[test]
+    fn print_outputs_its_value() {
+        for options in all_options() {
+            assert_eq!(output("print(2 + 3)", options), vec![5]);
+        }
+        // The addition is done by the VM when it isn't folded
+        let unfolded = CompilerOptions { fold_constants: false, ..CompilerOptions::default() };
+        assert_eq!(output("print(2 + 3)", unfolded), vec![5]);
+    }
+
+}
@@ -0,0 +1,107 @@
+/*
+Goal: Rebuild the expression trees of a straight-line arithmetic program.
+    - LOAD creates a value in a register
+    - ADD/SUB/MUL/DIV consume their source registers and create a BinOP in the result register
+    - A value that is overwritten or left in a register at the end is a statement
+ */
+
+use crate::compiler::node::{Node, ValueNode, Operator};
+use crate::vm::instruction::Instruction;
+use crate::vm::machine::REGISTERS;
+use std::fmt::{Debug, Formatter};
+
+pub enum DecompileError {
+    UnsupportedInstruction(usize),  // Address of the instruction
+    UndefinedRegister(usize, u8),   // Address of the instruction and register read before being written
+}
+
+impl Debug for DecompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecompileError::UnsupportedInstruction(address) => write!(f, "Unsupported Instruction at 0x{:04X}", address)?,
+            DecompileError::UndefinedRegister(address, reg) => write!(f, "Undefined Register r{:X} at 0x{:04X}", reg, address)?,
+        }
+        Ok(())
+    }
+}
+
+fn take(registers: &mut [Option<(usize, Node)>], address: usize, reg: u8) -> Result<(usize, Node), DecompileError> {
+    return match registers.get_mut(reg as usize).and_then(|value| value.take()) {
+        Some(value) => Ok(value),
+        None => Err(DecompileError::UndefinedRegister(address, reg)),
+    }
+}
+
+pub fn decompile(program: &[Instruction]) -> Result<Vec<Node>, DecompileError> {
+    // (address of the first instruction of the expression, expression)
+    let mut registers: Vec<Option<(usize, Node)>> = (0..REGISTERS).map(|_| None).collect();
+    let mut statements: Vec<(usize, Node)> = vec![];
+
+    for (address, instruction) in program.iter().enumerate() {
+        let (reg_result, value) = match instruction {
+            Instruction::Load(reg, value) => (*reg, (address, Node::Value(ValueNode::U8(*value)))),
+            Instruction::Add(reg_result, reg_a, reg_b) | Instruction::Sub(reg_result, reg_a, reg_b) |
+            Instruction::Mul(reg_result, reg_a, reg_b) | Instruction::Div(reg_result, reg_a, reg_b) |
+            Instruction::Mod(reg_result, reg_a, reg_b) => {
+                let op = match instruction {
+                    Instruction::Add(_, _, _) => Operator::PLUS,
+                    Instruction::Sub(_, _, _) => Operator::MINUS,
+                    Instruction::Mul(_, _, _) => Operator::MULTIPLY,
+                    Instruction::Mod(_, _, _) => Operator::MODULO,
+                    _ => Operator::DIVIDE,
+                };
+                let (start_a, left) = take(&mut registers, address, *reg_a)?;
+                // Both operands are the same expression when they are the same register
+                let (start_b, right) = if reg_a == reg_b {
+                    (start_a, left.clone())
+                } else {
+                    take(&mut registers, address, *reg_b)?
+                };
+                (*reg_result, (start_a.min(start_b), Node::BinOP(Box::new(left), op, Box::new(right))))
+            }
+            Instruction::Halt() => break,
+            _ => return Err(DecompileError::UnsupportedInstruction(address)),
+        };
+        if reg_result as usize >= REGISTERS {
+            return Err(DecompileError::UndefinedRegister(address, reg_result));
+        }
+        // The previous value of the register was never used
+        if let Some(statement) = registers[reg_result as usize].replace(value) {
+            statements.push(statement);
+        }
+    }
+
+    statements.extend(registers.into_iter().flatten());
+    statements.sort_by_key(|(start, _)| *start);
+    return Ok(statements.into_iter().map(|(_, node)| node).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compiler::{compile_with_options, CompilerOptions};
+    use crate::compiler::parser::parse;
+
+    fn decompile_to_string(program: &[Instruction]) -> Vec<String> {
+        return decompile(program).unwrap().iter().map(|node| format!("{}", node)).collect();
+    }
+
+    #[test]
+    fn expression_tree_is_recovered() {
+        let options = CompilerOptions { fold_constants: false, ..CompilerOptions::default() };
+        let program = compile_with_options(parse("4 + 5 * 2").unwrap(), options).unwrap();
+        assert_eq!(decompile_to_string(&program), vec!["4 + (5 * 2)"]);
+    }
+
+    #[test]
+    fn register_read_twice() {
+        let program = [Instruction::Load(0, 3), Instruction::Add(1, 0, 0)];
+        assert_eq!(decompile_to_string(&program), vec!["3 + 3"]);
+    }
+
+    #[test]
+    fn register_read_before_being_written() {
+        assert!(matches!(decompile(&[Instruction::Add(1, 0, 0)]), Err(DecompileError::UndefinedRegister(0, 0))));
+    }
+
+}
@@ -0,0 +1,167 @@
+/*
+Goal: Convert source text to the tokens read by the parser.
+    - Whitespace is skipped
+    - Every token keeps its line and column (both start at 1) and its length for error reporting
+    - Numbers must fit in 16 bits
+
+Eg: let a = 4 + 5; -> [Let, Name(a), Equal, Number(4), Plus, Number(5), Semicolon]
+ */
+
+use std::fmt::{Debug, Formatter};
+
+pub enum LexError {
+    UnexpectedCharacter { character: char, line: usize, col: usize },
+    NumberTooLarge { text: String, line: usize, col: usize },
+}
+
+impl Debug for LexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter { character, line, col } => write!(f, "Unexpected Character: '{}' at {}:{}", character, line, col)?,
+            LexError::NumberTooLarge { text, line, col } => write!(f, "Number Too Large: {} doesn't fit in 16 bits (0-65535) at {}:{}", text, line, col)?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum TokenKind {
+    Number(u16),
+    Name(String),
+    Let,
+    Print,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Equal,
+    Semicolon,
+    LeftParen,
+    RightParen,
+}
+
+impl Debug for TokenKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::Number(value) => write!(f, "{}", value)?,
+            TokenKind::Name(name) => write!(f, "{}", name)?,
+            TokenKind::Let => write!(f, "let")?,
+            TokenKind::Print => write!(f, "print")?,
+            TokenKind::Plus => write!(f, "+")?,
+            TokenKind::Minus => write!(f, "-")?,
+            TokenKind::Star => write!(f, "*")?,
+            TokenKind::Slash => write!(f, "/")?,
+            TokenKind::Equal => write!(f, "=")?,
+            TokenKind::Semicolon => write!(f, ";")?,
+            TokenKind::LeftParen => write!(f, "(")?,
+            TokenKind::RightParen => write!(f, ")")?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub col: usize,
+    pub length: usize,  // Number of characters
+}
+
+impl Debug for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} at {}:{}", self.kind, self.line, self.col)?;
+        Ok(())
+    }
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>, LexError> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    let mut line = 1;
+    let mut col = 1;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            col = 1;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            col += 1;
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let kind = if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.parse::<u16>() {
+                Ok(value) => TokenKind::Number(value),
+                Err(_) => return Err(LexError::NumberTooLarge { text, line, col }),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.as_str() {
+                "let" => TokenKind::Let,
+                "print" => TokenKind::Print,
+                _ => TokenKind::Name(text),
+            }
+        } else {
+            i += 1;
+            match c {
+                '+' => TokenKind::Plus,
+                '-' => TokenKind::Minus,
+                '*' => TokenKind::Star,
+                '/' => TokenKind::Slash,
+                '=' => TokenKind::Equal,
+                ';' => TokenKind::Semicolon,
+                '(' => TokenKind::LeftParen,
+                ')' => TokenKind::RightParen,
+                _ => return Err(LexError::UnexpectedCharacter { character: c, line, col }),
+            }
+        };
+        tokens.push(Token { kind, line, col, length: i - start });
+        col += i - start;
+    }
+    return Ok(tokens);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(kind: TokenKind, line: usize, col: usize, length: usize) -> Token {
+        return Token { kind, line, col, length };
+    }
+
+    #[test]
+    fn definition_is_tokenized() {
+        assert_eq!(tokenize("let a = 4 + 5;").unwrap(), vec![
+            token(TokenKind::Let, 1, 1, 3),
+            token(TokenKind::Name("a".to_string()), 1, 5, 1),
+            token(TokenKind::Equal, 1, 7, 1),
+            token(TokenKind::Number(4), 1, 9, 1),
+            token(TokenKind::Plus, 1, 11, 1),
+            token(TokenKind::Number(5), 1, 13, 1),
+            token(TokenKind::Semicolon, 1, 14, 1),
+        ]);
+    }
+
+    #[test]
+    fn positions_continue_on_the_next_lines() {
+        let tokens = tokenize("let a = 4;\n  print(a1 * 300)").unwrap();
+        let positions: Vec<(usize, usize, usize)> = tokens.iter().map(|token| (token.line, token.col, token.length)).collect();
+        assert_eq!(positions[5..], [(2, 3, 5), (2, 8, 1), (2, 9, 2), (2, 12, 1), (2, 14, 3), (2, 17, 1)]);
+        assert!(matches!(tokenize("let a = 4;\n  a $ 1"), Err(LexError::UnexpectedCharacter { character: '$', line: 2, col: 5 })));
+        assert!(matches!(tokenize("print(70000)"), Err(LexError::NumberTooLarge { ref text, line: 1, col: 7 }) if text == "70000"));
+    }
+}
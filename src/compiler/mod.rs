@@ -1,2 +1,6 @@
 pub mod node;
-pub mod compiler;
\ No newline at end of file
+pub mod compiler;
+pub mod decompiler;
+pub mod parser;
+pub mod lexer;
+pub mod optimizer;
\ No newline at end of file
@@ -1,8 +1,18 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone)]
 pub enum Node {
     Value(ValueNode),
     BinOP(Box<Node>, Operator, Box<Node>),
+    UnaryOp(UnaryOperator, Box<Node>),
     VariableDefinition(String, Box<Node>),
+    VariableAssignment(String, Box<Node>), // Replace the value of a defined variable
     VariableCall(String),
+    Intrinsic(String, Vec<Node>),   // Call to a VM instruction that has no syntax, eg: min(a, b)
+    Ternary(Box<Node>, Box<Node>, Box<Node>), // [arg1] if [arg0] isn't 0 else [arg2]
+    If(Box<Node>, Vec<Node>, Vec<Node>), // Run [arg1] if [arg0] isn't 0 else [arg2]
+    While(Box<Node>, Vec<Node>),    // Run [arg1] as long as [arg0] isn't 0
+    Print(Box<Node>),               // Write the value to the VM output, the high byte first for 16 bits values
 }
 
 impl Node {
@@ -11,20 +21,151 @@ impl Node {
         return match self {
             Node::Value(_) => 0,
             Node::BinOP(_, _, _) => 1,
+            Node::UnaryOp(_, _) => 1,
             Node::VariableDefinition(_, _) => 2,
+            Node::VariableAssignment(_, _) => 2,
             Node::VariableCall(_) => 0,
+            Node::Intrinsic(_, _) => 1,
+            Node::Ternary(_, _, _) => 1,
+            Node::If(_, _, _) => 2,
+            Node::While(_, _) => 2,
+            Node::Print(_) => 2,
         }
     }
 
 }
 
+// Wrap the operands that are operations themselves in parentheses so the priority stays visible
+fn fmt_operand(node: &Node, f: &mut Formatter<'_>) -> std::fmt::Result {
+    if let Node::BinOP(_, _, _) = node {
+        write!(f, "({})", node)?;
+    } else {
+        write!(f, "{}", node)?;
+    }
+    Ok(())
+}
+
+fn fmt_body(body: &[Node], f: &mut Formatter<'_>) -> std::fmt::Result {
+    for (i, node) in body.iter().enumerate() {
+        if i > 0 {
+            write!(f, ";")?;
+        }
+        write!(f, " {}", node)?;
+    }
+    write!(f, " ")?;
+    Ok(())
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Node::Value(value) => write!(f, "{}", value)?,
+            Node::BinOP(left, op, right) => {
+                fmt_operand(left, f)?;
+                write!(f, " {} ", op)?;
+                fmt_operand(right, f)?;
+            }
+            Node::UnaryOp(op, value) => {
+                write!(f, "{}", op)?;
+                fmt_operand(value, f)?;
+            }
+            Node::VariableDefinition(name, value) => write!(f, "let {} = {}", name, value)?,
+            Node::VariableAssignment(name, value) => write!(f, "{} = {}", name, value)?,
+            Node::VariableCall(name) => write!(f, "{}", name)?,
+            Node::Ternary(cond, a, b) => {
+                fmt_operand(cond, f)?;
+                write!(f, " ? ")?;
+                fmt_operand(a, f)?;
+                write!(f, " : ")?;
+                fmt_operand(b, f)?;
+            }
+            Node::If(cond, then_body, else_body) => {
+                write!(f, "if {} {{", cond)?;
+                fmt_body(then_body, f)?;
+                write!(f, "}}")?;
+                if !else_body.is_empty() {
+                    write!(f, " else {{")?;
+                    fmt_body(else_body, f)?;
+                    write!(f, "}}")?;
+                }
+            }
+            Node::While(cond, body) => {
+                write!(f, "while {} {{", cond)?;
+                fmt_body(body, f)?;
+                write!(f, "}}")?;
+            }
+            Node::Print(value) => write!(f, "print({})", value)?,
+            Node::Intrinsic(name, arguments) => {
+                write!(f, "{}(", name)?;
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", argument)?;
+                }
+                write!(f, ")")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 pub enum ValueNode {
     U8(u8),
+    U16(u16),   // Kept in 2 registers once compiled, the high byte first
 }
 
+// Comparisons give a boolean: 1 when true, 0 when false
+#[derive(Clone)]
 pub enum Operator {
     PLUS,
     MINUS,
     MULTIPLY,
-    DIVIDE
+    DIVIDE,
+    MODULO,
+    LESS,
+    EQUAL,
+    GREATER,
+}
+
+// NEGATE wraps like a subtraction from 0: -5 is 251
+#[derive(Clone)]
+pub enum UnaryOperator {
+    NEGATE,
+}
+
+impl Display for ValueNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueNode::U8(value) => write!(f, "{}", value)?,
+            ValueNode::U16(value) => write!(f, "{}", value)?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operator::PLUS => write!(f, "+")?,
+            Operator::MINUS => write!(f, "-")?,
+            Operator::MULTIPLY => write!(f, "*")?,
+            Operator::DIVIDE => write!(f, "/")?,
+            Operator::MODULO => write!(f, "%")?,
+            Operator::LESS => write!(f, "<")?,
+            Operator::EQUAL => write!(f, "==")?,
+            Operator::GREATER => write!(f, ">")?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for UnaryOperator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnaryOperator::NEGATE => write!(f, "-")?,
+        }
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,258 @@
+/*
+Goal: Simplify the AST before compiling it.
+    - BinOP of two values -> Value computed at compile time
+    - Operations that overflow or divide by zero are kept, the VM decides what they give (ArithmeticMode, DivZeroMode)
+    - Statements without effect are removed (remove_dead_code), this assumes the VM wraps on overflow (ArithmeticMode::Wrapping):
+        - Expressions that can't fault (no DIVIDE/MODULO) of values and defined variables, except the last top level one which is the result
+        - While with a condition of 0
+        - If with a constant condition is replaced by the branch taken
+      Bodies defining variables are kept, their definitions are reserved by the compiler even if they don't run
+
+Eg: 4 + 5 * 2 -> 14
+Eg: let a = 1; a + 2; print(a) -> let a = 1; print(a)
+ */
+
+use crate::compiler::node::{Node, ValueNode, Operator, UnaryOperator};
+
+fn evaluate(a: u8, op: &Operator, b: u8) -> Option<u8> {
+    return match op {
+        Operator::PLUS => a.checked_add(b),
+        Operator::MINUS => a.checked_sub(b),
+        Operator::MULTIPLY => a.checked_mul(b),
+        Operator::DIVIDE => a.checked_div(b),
+        Operator::MODULO => a.checked_rem(b),
+        Operator::LESS => Some((a < b) as u8),
+        Operator::EQUAL => Some((a == b) as u8),
+        Operator::GREATER => Some((a > b) as u8),
+    }
+}
+
+fn fold_body(body: Vec<Node>) -> Vec<Node> {
+    return body.into_iter().map(fold_constants).collect();
+}
+
+pub fn fold_constants(node: Node) -> Node {
+    return match node {
+        Node::BinOP(left, op, right) => {
+            let left = fold_constants(*left);
+            let right = fold_constants(*right);
+            if let (Node::Value(ValueNode::U8(a)), Node::Value(ValueNode::U8(b))) = (&left, &right) {
+                if let Some(value) = evaluate(*a, &op, *b) {
+                    return Node::Value(ValueNode::U8(value));
+                }
+            }
+            Node::BinOP(Box::new(left), op, Box::new(right))
+        }
+        Node::UnaryOp(op, value) => {
+            let value = fold_constants(*value);
+            if let Node::Value(ValueNode::U8(a)) = &value {
+                let result = match op {
+                    UnaryOperator::NEGATE => evaluate(0, &Operator::MINUS, *a),
+                };
+                if let Some(result) = result {
+                    return Node::Value(ValueNode::U8(result));
+                }
+            }
+            Node::UnaryOp(op, Box::new(value))
+        }
+        Node::VariableDefinition(name, value) => Node::VariableDefinition(name, Box::new(fold_constants(*value))),
+        Node::VariableAssignment(name, value) => Node::VariableAssignment(name, Box::new(fold_constants(*value))),
+        Node::Intrinsic(name, arguments) => Node::Intrinsic(name, fold_body(arguments)),
+        Node::Ternary(cond, a, b) => Node::Ternary(Box::new(fold_constants(*cond)), Box::new(fold_constants(*a)), Box::new(fold_constants(*b))),
+        Node::If(cond, then_body, else_body) => Node::If(Box::new(fold_constants(*cond)), fold_body(then_body), fold_body(else_body)),
+        Node::While(cond, body) => Node::While(Box::new(fold_constants(*cond)), fold_body(body)),
+        Node::Print(value) => Node::Print(Box::new(fold_constants(*value))),
+        Node::Value(_) | Node::VariableCall(_) => node,
+    }
+}
+
+fn constant(node: &Node) -> Option<u16> {
+    return match node {
+        Node::Value(ValueNode::U8(value)) => Some(*value as u16),
+        Node::Value(ValueNode::U16(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+fn defines_nothing(body: &[Node]) -> bool {
+    return body.iter().all(|node| match node {
+        Node::VariableDefinition(_, _) => false,
+        Node::If(_, then_body, else_body) => defines_nothing(then_body) && defines_nothing(else_body),
+        Node::While(_, body) => defines_nothing(body),
+        _ => true,
+    });
+}
+
+// Whether the expression compiles and runs without an effect, [defined] are the variables it can read
+fn is_pure(node: &Node, defined: &[String]) -> bool {
+    return match node {
+        Node::Value(_) => true,
+        Node::VariableCall(name) => defined.contains(name),
+        Node::BinOP(_, Operator::DIVIDE, _) | Node::BinOP(_, Operator::MODULO, _) => false,
+        Node::BinOP(left, _, right) => is_pure(left, defined) && is_pure(right, defined),
+        Node::UnaryOp(_, value) => is_pure(value, defined),
+        _ => false,
+    }
+}
+
+// Definitions made in a nested body aren't used to decide what is pure after it
+fn remove_in_nested(body: Vec<Node>, defined: &[String]) -> Vec<Node> {
+    let body = body.into_iter().map(|node| (node, ())).collect();
+    return remove_in_body(body, &mut defined.to_vec(), false).into_iter().map(|(node, _)| node).collect();
+}
+
+fn remove_in_body<T: Clone>(body: Vec<(Node, T)>, defined: &mut Vec<String>, keep_last: bool) -> Vec<(Node, T)> {
+    let count = body.len();
+    let mut result = vec![];
+    for (i, (node, data)) in body.into_iter().enumerate() {
+        match node {
+            Node::If(cond, then_body, else_body) => {
+                if let Some(value) = constant(&cond) {
+                    if defines_nothing(&then_body) && defines_nothing(&else_body) {
+                        let taken = if value != 0 { then_body } else { else_body };
+                        let taken = taken.into_iter().map(|node| (node, data.clone())).collect();
+                        result.extend(remove_in_body(taken, defined, false));
+                        continue;
+                    }
+                }
+                let then_body = remove_in_nested(then_body, defined);
+                let else_body = remove_in_nested(else_body, defined);
+                result.push((Node::If(cond, then_body, else_body), data));
+            }
+            Node::While(cond, body) => {
+                if constant(&cond) == Some(0) && defines_nothing(&body) {
+                    continue;
+                }
+                let body = remove_in_nested(body, defined);
+                result.push((Node::While(cond, body), data));
+            }
+            Node::VariableDefinition(name, value) => {
+                defined.push(name.clone());
+                result.push((Node::VariableDefinition(name, value), data));
+            }
+            node => {
+                if !is_pure(&node, defined) || (keep_last && i + 1 == count) {
+                    result.push((node, data));
+                }
+            }
+        }
+    }
+    return result;
+}
+
+pub fn remove_dead_code(ast: Vec<Node>) -> Vec<Node> {
+    let ast = ast.into_iter().map(|node| (node, ())).collect();
+    return remove_dead_code_with(ast).into_iter().map(|(node, _)| node).collect();
+}
+
+// Same as remove_dead_code, each top level node keeps its [T] (eg: its span), the nodes of a replaced If get the If's
+pub fn remove_dead_code_with<T: Clone>(ast: Vec<(Node, T)>) -> Vec<(Node, T)> {
+    return remove_in_body(ast, &mut vec![], true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compiler::{compile_with_options, CompilerOptions};
+    use crate::vm::instruction::Instruction;
+    use crate::vm::machine::{VM, VmConfig, HaltReason, DivZeroMode};
+    use crate::vm::machine::tests::Capture;
+    use crate::compiler::parser::parse;
+
+    const OPERATORS: [Operator; 8] = [
+        Operator::PLUS, Operator::MINUS, Operator::MULTIPLY, Operator::DIVIDE,
+        Operator::MODULO, Operator::LESS, Operator::EQUAL, Operator::GREATER,
+    ];
+
+    fn operation(a: u8, op: Operator, b: u8) -> Node {
+        return Node::BinOP(Box::new(Node::Value(ValueNode::U8(a))), op, Box::new(Node::Value(ValueNode::U8(b))));
+    }
+
+    #[test]
+    fn folding_gives_what_the_vm_computes() {
+        for op in OPERATORS.iter() {
+            // Compiled once, the loads of the operands are patched for each pair
+            let program = compile_with_options(vec![Node::Print(Box::new(operation(0, op.clone(), 0)))], CompilerOptions { fold_constants: false, ..CompilerOptions::default() }).unwrap();
+            let (reg_a, reg_b) = match (&program[0], &program[1]) {
+                (Instruction::Load(reg_a, 0), Instruction::Load(reg_b, 0)) => (*reg_a, *reg_b),
+                _ => panic!("{:?} doesn't start with the loads of the operands", program),
+            };
+            let mut vm = VM::with_config(program, VmConfig { stack_size: 16, ..VmConfig::default() });
+            vm.set_div_zero_mode(DivZeroMode::YieldZero);
+            let capture = Capture::new();
+            vm.set_output(Box::new(capture.clone()));
+            for a in 0..=255u8 {
+                for b in 0..=255u8 {
+                    vm.patch(0, Instruction::Load(reg_a, a)).unwrap();
+                    vm.patch(1, Instruction::Load(reg_b, b)).unwrap();
+                    vm.reset();
+                    assert_eq!(vm.run(), HaltReason::Finished);
+                }
+            }
+            let output = capture.bytes();
+            assert_eq!(output.len(), 256 * 256);
+            for a in 0..=255u8 {
+                for b in 0..=255u8 {
+                    if let Node::Value(ValueNode::U8(folded)) = fold_constants(operation(a, op.clone(), b)) {
+                        assert_eq!(folded, output[a as usize * 256 + b as usize], "{} {} {}", a, op, b);
+                    }
+                }
+            }
+        }
+    }
+    fn to_string(ast: &[Node]) -> Vec<String> {
+        return ast.iter().map(|node| format!("{}", node)).collect();
+    }
+
+    #[test]
+    fn statements_without_effect_are_removed() {
+        let ast = parse("let a = 1; a + 2; -a; 5 / a; b + 1; print(a); a * 3").unwrap();
+        // The division can fault, b isn't defined, the last statement is the result
+        assert_eq!(to_string(&remove_dead_code(ast)), vec!["let a = 1", "5 / a", "b + 1", "print(a)", "a * 3"]);
+    }
+
+    #[test]
+    fn constant_conditions_are_removed() {
+        let print = |value| Node::Print(Box::new(Node::Value(ValueNode::U8(value))));
+        let define = || Node::VariableDefinition("a".to_string(), Box::new(Node::Value(ValueNode::U8(1))));
+        let zero = || Box::new(Node::Value(ValueNode::U8(0)));
+        let ast = vec![
+            (Node::If(Box::new(Node::Value(ValueNode::U16(0x0100))), vec![print(1), print(2)], vec![print(3)]), 1),
+            (Node::If(zero(), vec![print(4)], vec![]), 2),
+            (Node::If(zero(), vec![define()], vec![]), 3),
+            (Node::While(zero(), vec![print(5)]), 4),
+            (Node::While(zero(), vec![define()]), 5),
+            (Node::While(Box::new(Node::VariableCall("a".to_string())), vec![Node::Value(ValueNode::U8(6)), print(6)]), 6),
+        ];
+        let result = remove_dead_code_with(ast);
+        let result: Vec<(String, i32)> = result.into_iter().map(|(node, line)| (format!("{}", node), line)).collect();
+        assert_eq!(result, vec![
+            ("print(1)".to_string(), 1),
+            ("print(2)".to_string(), 1),
+            ("if 0 { let a = 1 }".to_string(), 3),
+            ("while 0 { let a = 1 }".to_string(), 5),
+            ("while a { print(6) }".to_string(), 6),
+        ]);
+    }
+
+    fn folded(source: &str) -> Node {
+        return fold_constants(parse(source).unwrap().remove(0));
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        assert!(matches!(folded("4 + 5 * 2"), Node::Value(ValueNode::U8(14))));
+        assert!(matches!(folded("(8 - 3) / 2 - -0"), Node::Value(ValueNode::U8(2))));
+        assert_eq!(format!("{}", folded("let a = 2 * 3; a")), "let a = 6");
+    }
+
+    #[test]
+    fn faulting_operations_are_kept() {
+        assert_eq!(format!("{}", folded("5 / 0")), "5 / 0");
+        assert_eq!(format!("{}", folded("5 / (2 - 2)")), "5 / 0");
+        assert_eq!(format!("{}", folded("200 + 100")), "200 + 100");
+        assert_eq!(format!("{}", folded("(2 - 3) * 4")), "(2 - 3) * 4");
+        assert_eq!(format!("{}", folded("-1")), "-1");
+    }
+
+}
@@ -0,0 +1,292 @@
+/*
+Goal: Convert the tokens of the lexer to the AST given to the compiler.
+    - Statements are separated by ; (optional after the last one)
+    - let NAME = EXPR -> VariableDefinition
+    - NAME = EXPR -> VariableAssignment
+    - print(EXPR) -> Print
+    - EXPR -> Value, VariableCall or BinOP
+    - Expressions can be nested up to MAX_DEPTH levels, each parenthesis, unary minus and operator is a level (parse_with_depth sets another limit)
+    - parse_with_spans also gives the position of each top level statement (the part of it on its first line)
+
+Grammar:
+    statement := "let" NAME "=" expr | NAME "=" expr | "print" "(" expr ")" | expr
+    expr := term (("+" | "-") term)*
+    term := factor (("*" | "/") factor)*
+    factor := NUMBER | NAME | "(" expr ")" | "-" factor
+
+Eg: let a = 4 + 5 * 2; a * 3
+ */
+
+use crate::compiler::node::{Node, ValueNode, Operator, UnaryOperator};
+use crate::compiler::lexer::{tokenize, LexError, Token, TokenKind};
+use crate::diagnostic::Span;
+use std::fmt::{Debug, Formatter};
+use std::cmp::max;
+
+// Each nesting level is a few recursive calls, this stays far below the stack size of a thread
+pub const MAX_DEPTH: usize = 256;
+
+pub enum ParseError {
+    Lex(LexError),
+    UnexpectedToken { found: String, expected: &'static str, line: usize, col: usize },
+    UnexpectedEnd { expected: &'static str },
+    TooDeep { limit: usize, line: usize, col: usize },
+}
+
+impl Debug for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Lex(err) => write!(f, "{:?}", err)?,
+            ParseError::UnexpectedToken { found, expected, line, col } => write!(f, "Unexpected Token: '{}' at {}:{}, expected {}", found, line, col, expected)?,
+            ParseError::UnexpectedEnd { expected } => write!(f, "Unexpected End: expected {}", expected)?,
+            ParseError::TooDeep { limit, line, col } => write!(f, "Too Deep: more than {} nested expressions at {}:{}", limit, line, col)?,
+        }
+        Ok(())
+    }
+}
+
+impl From<LexError> for ParseError {
+    fn from(err: LexError) -> Self {
+        return ParseError::Lex(err);
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,       // Number of parentheses and unary minus the current factor is in
+    max_depth: usize,   // Limit of depth and of the height of the trees built
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&TokenKind> {
+        return self.tokens.get(self.position).map(|token| &token.kind);
+    }
+
+    fn unexpected(&self, expected: &'static str) -> ParseError {
+        return match self.tokens.get(self.position) {
+            Some(token) => ParseError::UnexpectedToken { found: format!("{:?}", token.kind), expected, line: token.line, col: token.col },
+            None => ParseError::UnexpectedEnd { expected },
+        }
+    }
+
+    fn expect(&mut self, token: TokenKind, expected: &'static str) -> Result<(), ParseError> {
+        if self.peek() != Some(&token) {
+            return Err(self.unexpected(expected));
+        }
+        self.position += 1;
+        return Ok(());
+    }
+
+    // Span from the token at [start] to the last token read on the same line
+    fn span(&self, start: usize) -> Span {
+        let first = &self.tokens[start];
+        let last = self.tokens[start..self.position].iter()
+            .filter(|token| token.line == first.line)
+            .last()
+            .unwrap_or(first);
+        return Span { line: first.line, col_start: first.col, col_end: last.col + last.length };
+    }
+
+    fn statement(&mut self) -> Result<Node, ParseError> {
+        if self.peek() == Some(&TokenKind::Print) {
+            self.position += 1;
+            self.expect(TokenKind::LeftParen, "'('")?;
+            let (value, _) = self.expr()?;
+            self.expect(TokenKind::RightParen, "')'")?;
+            return Ok(Node::Print(Box::new(value)));
+        }
+        if self.peek() != Some(&TokenKind::Let) {
+            if let Some(TokenKind::Name(name)) = self.peek() {
+                let is_assignment = matches!(self.tokens.get(self.position + 1), Some(token) if token.kind == TokenKind::Equal);
+                if is_assignment {
+                    let name = name.clone();
+                    self.position += 2;
+                    let (value, _) = self.expr()?;
+                    return Ok(Node::VariableAssignment(name, Box::new(value)));
+                }
+            }
+            return Ok(self.expr()?.0);
+        }
+        self.position += 1;
+        let name = match self.peek() {
+            Some(TokenKind::Name(name)) => name.clone(),
+            _ => return Err(self.unexpected("a variable name")),
+        };
+        self.position += 1;
+        self.expect(TokenKind::Equal, "'='")?;
+        let (value, _) = self.expr()?;
+        return Ok(Node::VariableDefinition(name, Box::new(value)));
+    }
+
+    // The expressions are returned with the height of their tree, a chain of operators is as deep as nested parentheses for the compiler
+    fn expr(&mut self) -> Result<(Node, usize), ParseError> {
+        let (mut left, mut height) = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Plus) => Operator::PLUS,
+                Some(TokenKind::Minus) => Operator::MINUS,
+                _ => return Ok((left, height)),
+            };
+            let position = self.position;
+            self.position += 1;
+            let (right, right_height) = self.term()?;
+            height = self.grow(max(height, right_height), position)?;
+            left = Node::BinOP(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn term(&mut self) -> Result<(Node, usize), ParseError> {
+        let (mut left, mut height) = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Star) => Operator::MULTIPLY,
+                Some(TokenKind::Slash) => Operator::DIVIDE,
+                _ => return Ok((left, height)),
+            };
+            let position = self.position;
+            self.position += 1;
+            let (right, right_height) = self.factor()?;
+            height = self.grow(max(height, right_height), position)?;
+            left = Node::BinOP(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn too_deep(&self, position: usize) -> ParseError {
+        let token = &self.tokens[position];
+        return ParseError::TooDeep { limit: self.max_depth, line: token.line, col: token.col };
+    }
+
+    // Enter a nested factor, the caller leaves it by decrementing depth
+    fn nest(&mut self) -> Result<(), ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(self.too_deep(self.position));
+        }
+        self.depth += 1;
+        return Ok(());
+    }
+
+    // Height of the node made by the operator at [position] over operands at most [height] high
+    fn grow(&self, height: usize, position: usize) -> Result<usize, ParseError> {
+        if height >= self.max_depth {
+            return Err(self.too_deep(position));
+        }
+        return Ok(height + 1);
+    }
+
+    fn factor(&mut self) -> Result<(Node, usize), ParseError> {
+        let node = match self.peek() {
+            Some(TokenKind::Number(value)) => if *value > u8::MAX as u16 {
+                Node::Value(ValueNode::U16(*value))
+            } else {
+                Node::Value(ValueNode::U8(*value as u8))
+            },
+            Some(TokenKind::Name(name)) => Node::VariableCall(name.clone()),
+            Some(TokenKind::LeftParen) => {
+                self.nest()?;
+                self.position += 1;
+                let node = self.expr()?;
+                self.expect(TokenKind::RightParen, "')'")?;
+                self.depth -= 1;
+                return Ok(node);
+            }
+            Some(TokenKind::Minus) => {
+                let position = self.position;
+                self.nest()?;
+                self.position += 1;
+                let (value, height) = self.factor()?;
+                self.depth -= 1;
+                let height = self.grow(height, position)?;
+                return Ok((Node::UnaryOp(UnaryOperator::NEGATE, Box::new(value)), height));
+            }
+            _ => return Err(self.unexpected("a number, a variable, '(' or '-'")),
+        };
+        self.position += 1;
+        return Ok((node, 0));
+    }
+
+}
+
+pub fn parse(source: &str) -> Result<Vec<Node>, ParseError> {
+    return parse_with_depth(source, MAX_DEPTH);
+}
+
+// Same as parse but with at most [max_depth] levels of nested expressions
+pub fn parse_with_depth(source: &str, max_depth: usize) -> Result<Vec<Node>, ParseError> {
+    return Ok(parse_statements(source, max_depth)?.into_iter().map(|(node, _)| node).collect());
+}
+
+// Same as parse but with the span of each top level node
+pub fn parse_with_spans(source: &str) -> Result<(Vec<Node>, Vec<Span>), ParseError> {
+    return Ok(parse_statements(source, MAX_DEPTH)?.into_iter().unzip());
+}
+
+fn parse_statements(source: &str, max_depth: usize) -> Result<Vec<(Node, Span)>, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        depth: 0,
+        max_depth,
+    };
+    let mut ast = vec![];
+    while parser.peek().is_some() {
+        // Empty statements are skipped
+        if parser.peek() == Some(&TokenKind::Semicolon) {
+            parser.position += 1;
+            continue;
+        }
+        let start = parser.position;
+        let node = parser.statement()?;
+        ast.push((node, parser.span(start)));
+        if parser.peek().is_some() {
+            parser.expect(TokenKind::Semicolon, "';'")?;
+        }
+    }
+    return Ok(ast);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting_past_the_limit_is_an_error() {
+        let source = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(matches!(parse(&source), Err(ParseError::TooDeep { limit: MAX_DEPTH, line: 1, col }) if col == MAX_DEPTH + 1));
+        assert!(matches!(parse(&"-".repeat(100_000)), Err(ParseError::TooDeep { .. })));
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses() {
+        let source = format!("{}1{}", "(".repeat(3), ")".repeat(3));
+        assert!(parse_with_depth(&source, 3).is_ok());
+        assert!(matches!(parse_with_depth(&source, 2), Err(ParseError::TooDeep { limit: 2, .. })));
+        assert!(matches!(parse_with_depth("--1", 1), Err(ParseError::TooDeep { limit: 1, line: 1, col: 2 })));
+    }
+
+    #[test]
+    fn long_operator_chain_is_too_deep() {
+        // Parsed as ((1 + 1) + 1) + ..., as deep as the parentheses
+        let source = format!("1{}", "+1".repeat(100_000));
+        assert!(matches!(parse(&source), Err(ParseError::TooDeep { limit: MAX_DEPTH, line: 1, col }) if col == MAX_DEPTH * 2 + 2));
+        assert!(matches!(parse(&format!("1{}", "*1".repeat(100_000))), Err(ParseError::TooDeep { .. })));
+        // The levels of the parentheses and of the operators in them add up
+        assert!(parse_with_depth("1+1*1", 2).is_ok());
+        assert!(matches!(parse_with_depth("1+1+1", 1), Err(ParseError::TooDeep { limit: 1, line: 1, col: 4 })));
+        assert!(matches!(parse_with_depth("(1+1)+1", 1), Err(ParseError::TooDeep { limit: 1, line: 1, col: 6 })));
+        assert!(matches!(parse_with_depth("-(1+1*1)", 2), Err(ParseError::TooDeep { limit: 2, line: 1, col: 1 })));
+    }
+
+    #[test]
+    fn statement_spans() {
+        let (ast, spans) = parse_with_spans("let a = 4 + 5;\n  print(a);;\na *\n3").unwrap();
+        assert_eq!(ast.len(), 3);
+        assert_eq!(spans, vec![
+            Span { line: 1, col_start: 1, col_end: 14 },
+            Span { line: 2, col_start: 3, col_end: 11 },
+            Span { line: 3, col_start: 1, col_end: 4 },
+        ]);
+    }
+
+}
@@ -0,0 +1,236 @@
+/*
+Goal: Report the errors of every stage in the same shape.
+    - Each error type converts to a Diagnostic (stage, message and position when known)
+    - Diagnostics collects them so a front-end can show every problem at once
+ */
+
+use crate::assembler::assembler::AssemblerError;
+use crate::compiler::compiler::CompileError;
+use crate::compiler::decompiler::DecompileError;
+use crate::compiler::lexer::LexError;
+use crate::compiler::parser::ParseError;
+use crate::pipeline::PipelineError;
+use crate::vm::machine::VmError;
+use std::fmt::{Debug, Formatter};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    Lex,
+    Parse,
+    Compile,
+    Assemble,
+    Decompile,
+    Run,
+}
+
+// Position in the source of what produced an instruction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span {
+    pub line: usize,
+    pub col_start: usize,
+    pub col_end: usize,     // Exclusive
+}
+
+pub struct Diagnostic {
+    pub stage: Stage,
+    pub message: String,
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+impl Debug for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:?}]", self.stage)?;
+        if let Some(line) = self.line {
+            write!(f, " {}", line)?;
+            if let Some(col) = self.col {
+                write!(f, ":{}", col)?;
+            }
+        }
+        write!(f, " {}", self.message)?;
+        Ok(())
+    }
+}
+
+impl From<AssemblerError> for Diagnostic {
+    fn from(err: AssemblerError) -> Self {
+        let line = match &err {
+            AssemblerError::BadLiteral { line, .. } => Some(*line),
+            AssemblerError::ImmediateOutOfRange { line, .. } => Some(*line),
+            AssemblerError::DuplicateLabel { line, .. } => Some(*line),
+            AssemblerError::ProgramTooLarge { line, .. } => Some(*line),
+            AssemblerError::LabelNotAllowed { line } => Some(*line),
+            AssemblerError::AliasConflict { line, .. } => Some(*line),
+            _ => None,
+        };
+        return Diagnostic {
+            stage: Stage::Assemble,
+            message: format!("{:?}", err),
+            line,
+            col: None,
+        }
+    }
+}
+
+impl From<LexError> for Diagnostic {
+    fn from(err: LexError) -> Self {
+        let (line, col) = match &err {
+            LexError::UnexpectedCharacter { line, col, .. } => (*line, *col),
+            LexError::NumberTooLarge { line, col, .. } => (*line, *col),
+        };
+        return Diagnostic {
+            stage: Stage::Lex,
+            message: format!("{:?}", err),
+            line: Some(line),
+            col: Some(col),
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(err: ParseError) -> Self {
+        if let ParseError::Lex(err) = err {
+            return Diagnostic::from(err);
+        }
+        let (line, col) = match &err {
+            ParseError::Lex(_) => (None, None),
+            ParseError::UnexpectedToken { line, col, .. } => (Some(*line), Some(*col)),
+            ParseError::UnexpectedEnd { .. } => (None, None),
+            ParseError::TooDeep { line, col, .. } => (Some(*line), Some(*col)),
+        };
+        return Diagnostic {
+            stage: Stage::Parse,
+            message: format!("{:?}", err),
+            line,
+            col,
+        }
+    }
+}
+
+impl From<CompileError> for Diagnostic {
+    fn from(err: CompileError) -> Self {
+        return Diagnostic {
+            stage: Stage::Compile,
+            message: format!("{:?}", err),
+            line: None,
+            col: None,
+        }
+    }
+}
+
+impl From<DecompileError> for Diagnostic {
+    fn from(err: DecompileError) -> Self {
+        return Diagnostic {
+            stage: Stage::Decompile,
+            message: format!("{:?}", err),
+            line: None,
+            col: None,
+        }
+    }
+}
+
+impl From<VmError> for Diagnostic {
+    fn from(err: VmError) -> Self {
+        if let VmError::Located { error, span } = err {
+            return Diagnostic {
+                stage: Stage::Run,
+                message: format!("{:?}", error),
+                line: Some(span.line),
+                col: Some(span.col_start),
+            }
+        }
+        return Diagnostic {
+            stage: Stage::Run,
+            message: format!("{:?}", err),
+            line: None,
+            col: None,
+        }
+    }
+}
+
+impl From<PipelineError> for Diagnostic {
+    fn from(err: PipelineError) -> Self {
+        return match err {
+            PipelineError::Parse(err) => Diagnostic::from(err),
+            PipelineError::Compile(err) => Diagnostic::from(err),
+            PipelineError::Run(err) => Diagnostic::from(err),
+            PipelineError::CycleLimit(_) => Diagnostic {
+                stage: Stage::Run,
+                message: format!("{:?}", err),
+                line: None,
+                col: None,
+            },
+        }
+    }
+}
+
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+
+    pub fn new() -> Diagnostics {
+        return Diagnostics {
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn report<E: Into<Diagnostic>>(&mut self, err: E) {
+        self.diagnostics.push(err.into());
+    }
+
+    // Report the error of [result] if any and return its value
+    pub fn collect<T, E: Into<Diagnostic>>(&mut self, result: Result<T, E>) -> Option<T> {
+        return match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.report(err);
+                None
+            }
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        return &self.diagnostics;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.diagnostics.is_empty();
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler::{assemble, assemble_program};
+    use crate::vm::machine::{VM, HaltReason};
+
+    #[test]
+    fn assembler_errors_keep_their_line() {
+        let sources = ["HALT\n.alias _ r1", "HALT\n$x EQ r0 $x1", "HALT\nLOAD r0 0x1FF", "HALT\n$x HALT\n$x HALT"];
+        let lines = [2, 2, 2, 3];
+        for (source, line) in sources.iter().zip(lines.iter()) {
+            let diagnostic = Diagnostic::from(assemble(source.to_string()).err().unwrap());
+            assert_eq!(diagnostic.stage, Stage::Assemble);
+            assert_eq!(diagnostic.line, Some(*line), "{}", source);
+        }
+    }
+
+    #[test]
+    fn fault_reports_the_source_line() {
+        let program = assemble_program("LOAD r0 0x01\n# Divide by 0\nDIV r2 r0 r1\nHALT".to_string()).unwrap();
+        let mut vm = VM::new(program.instructions);
+        vm.set_spans(program.spans);
+        let err = match vm.run() {
+            HaltReason::Fault(err) => err,
+            reason => panic!("The program didn't fault: {:?}", reason),
+        };
+        assert_eq!(err, VmError::Located { error: Box::new(VmError::DivByZero { pc: 1 }), span: Span { line: 3, col_start: 1, col_end: 13 } });
+        let diagnostic = Diagnostic::from(err);
+        assert_eq!((diagnostic.stage, diagnostic.line, diagnostic.col), (Stage::Run, Some(3), Some(1)));
+        assert_eq!(diagnostic.message, "Division By Zero at 0x0001");
+    }
+
+}
@@ -1,4 +1,4 @@
-use crate::vm::machine::VM;
+use crate::vm::machine::{VM, HaltReason};
 use crate::vm::instruction::Instruction;
 use crate::compiler::node::{Node, ValueNode, Operator};
 use crate::compiler::compiler::compile;
@@ -6,6 +6,8 @@ use crate::compiler::compiler::compile;
 mod vm;
 mod assembler;
 mod compiler;
+mod diagnostic;
+mod pipeline;
 
 fn main() {
     let ast = vec![Node::VariableDefinition("a".to_string(), Box::new(Node::BinOP(
@@ -21,17 +23,29 @@ fn main() {
                      Operator::MULTIPLY,
                      Box::new(Node::Value(ValueNode::U8(3)))
     )];
-    let program = compile(ast);
+    let program = match compile(ast) {
+        Ok(program) => program,
+        Err(err) => {
+            println!("Error: {:?}", err);
+            return;
+        }
+    };
 
     // let program = assembler::assembler::assemble(std::fs::read_to_string("res\\main.mvm").expect("Failed to read file!")).expect("Failed to assembler file!");
 
     println!("--- Program ---");
-    for instruction in program.iter() {
-        println!("{:?}", instruction);
+    if program.is_empty() {
+        println!("(empty)");
     }
+    print!("{}", assembler::assembler::listing(&program));
 
     let mut vm = VM::new(program);
-    vm.run();
+    match vm.run() {
+        HaltReason::Halted => {}
+        HaltReason::Finished => println!("\nWarning: program ended without HALT"),
+        HaltReason::Fault(err) => println!("\nError: {:?}", err),
+        HaltReason::CycleLimit => println!("\nWarning: program stopped after too many instructions"),
+    }
 
     println!("\n--- Registers ---");
     vm.print_registers();
@@ -0,0 +1,172 @@
+/*
+Goal: Run source text in a single call.
+    - Parse, fold the constants, remove the dead code, compile, remove the redundant instructions (each pass can be disabled), add a HALT and run the program
+    - Faults are located on the statement that caused them
+    - run_source_with_diagnostics reports the error of any stage to Diagnostics instead
+    - The VM is returned once the program stops so its registers and memory can be read
+    - Each stage keeps its own error
+ */
+
+use crate::assembler::peephole::peephole_with_spans;
+use crate::compiler::compiler::{compile_with_spans, CompileError, CompilerOptions};
+use crate::compiler::optimizer::{fold_constants, remove_dead_code_with};
+use crate::compiler::parser::{parse_with_spans, ParseError};
+use crate::compiler::node::Node;
+use crate::diagnostic::{Diagnostics, Span};
+use crate::vm::instruction::Instruction;
+use crate::vm::machine::{VM, VmError, HaltReason};
+use std::fmt::{Debug, Formatter};
+
+pub struct PipelineOptions {
+    pub fold_constants: bool,   // Compute the operations on constants before compiling
+    pub dead_code: bool,        // Remove the statements without effect before compiling
+    pub peephole: bool,         // Remove the instructions without effect after compiling
+    pub max_cycles: u64,        // Most instructions run before giving up
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        return PipelineOptions {
+            fold_constants: true,
+            dead_code: true,
+            peephole: true,
+            max_cycles: 1_000_000,
+        }
+    }
+}
+
+pub enum PipelineError {
+    Parse(ParseError),
+    Compile(CompileError),
+    Run(VmError),
+    CycleLimit(u64),    // The program didn't stop after the number of instructions
+}
+
+impl Debug for PipelineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Parse(err) => write!(f, "{:?}", err)?,
+            PipelineError::Compile(err) => write!(f, "{:?}", err)?,
+            PipelineError::Run(err) => write!(f, "{:?}", err)?,
+            PipelineError::CycleLimit(limit) => write!(f, "Cycle Limit: the program didn't stop after {} instructions", limit)?,
+        }
+        Ok(())
+    }
+}
+
+impl From<ParseError> for PipelineError {
+    fn from(err: ParseError) -> Self {
+        return PipelineError::Parse(err);
+    }
+}
+
+impl From<CompileError> for PipelineError {
+    fn from(err: CompileError) -> Self {
+        return PipelineError::Compile(err);
+    }
+}
+
+pub fn run_source(source: &str, options: PipelineOptions) -> Result<VM, PipelineError> {
+    let (ast, spans) = parse_with_spans(source)?;
+    let mut ast: Vec<(Node, Span)> = ast.into_iter().zip(spans.into_iter()).collect();
+    if options.fold_constants {
+        ast = ast.into_iter().map(|(node, span)| (fold_constants(node), span)).collect();
+    }
+    if options.dead_code {
+        ast = remove_dead_code_with(ast);
+    }
+    let (ast, spans): (Vec<Node>, Vec<Span>) = ast.into_iter().unzip();
+    // The constants are already folded
+    let compiler_options = CompilerOptions {
+        fold_constants: false,
+        ..CompilerOptions::default()
+    };
+    let (mut program, mut spans) = compile_with_spans(ast, &spans, compiler_options)?;
+    if options.peephole {
+        let (optimized, optimized_spans) = peephole_with_spans(&program, &spans);
+        program = optimized;
+        spans = optimized_spans;
+    }
+    program.push(Instruction::Halt());
+
+    let mut vm = VM::new(program);
+    vm.set_spans(spans);
+    return match vm.run_with_limit(options.max_cycles) {
+        HaltReason::Halted | HaltReason::Finished => Ok(vm),
+        HaltReason::Fault(err) => Err(PipelineError::Run(err)),
+        HaltReason::CycleLimit => Err(PipelineError::CycleLimit(options.max_cycles)),
+    }
+}
+
+// Same as run_source, the error is reported to [diagnostics] with its stage and position
+pub fn run_source_with_diagnostics(source: &str, options: PipelineOptions, diagnostics: &mut Diagnostics) -> Option<VM> {
+    return diagnostics.collect(run_source(source, options));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Stage;
+
+    #[test]
+    fn source_runs_to_its_result() {
+        let vm = run_source("let a = 4 + 5 * 2; a * 3", PipelineOptions::default()).unwrap();
+        assert_eq!(vm.register(1), Some(42));
+        let unfolded = run_source("let a = 4 + 5 * 2; a * 3", PipelineOptions { fold_constants: false, ..PipelineOptions::default() }).unwrap();
+        assert_eq!(unfolded.register(1), Some(42));
+        assert!(unfolded.cycles() > vm.cycles());
+    }
+
+    #[test]
+    fn each_stage_reports_its_error() {
+        assert!(matches!(run_source("let = 1", PipelineOptions::default()), Err(PipelineError::Parse(_))));
+        assert!(matches!(run_source("a + 1", PipelineOptions::default()), Err(PipelineError::Compile(CompileError::UndefinedVariable(_)))));
+        assert!(matches!(run_source("let a = 0; 5 / a", PipelineOptions::default()), Err(PipelineError::Run(VmError::Located { .. }))));
+        assert!(matches!(run_source("let a = 1; a + 2", PipelineOptions { max_cycles: 2, ..PipelineOptions::default() }), Err(PipelineError::CycleLimit(2))));
+    }
+
+    #[test]
+    fn fault_reports_the_line_of_its_statement() {
+        let err = match run_source("let a = 0;\nprint(5 / a);\nprint(a)", PipelineOptions::default()).err() {
+            Some(PipelineError::Run(err)) => err,
+            other => panic!("{:?}", other),
+        };
+        assert!(matches!(err, VmError::Located { ref error, span } if matches!(**error, VmError::DivByZero { .. }) && span.line == 2), "{:?}", err);
+    }
+
+    #[test]
+    fn each_pass_runs_when_enabled() {
+        let source = "let a = 4 + 5 * 2; a + 1; print(7); print(7); a * 3";
+        let none = || PipelineOptions { fold_constants: false, dead_code: false, peephole: false, ..PipelineOptions::default() };
+        let count = |options: PipelineOptions, mnemonic: &str| -> u64 {
+            let vm = run_source(source, options).unwrap();
+            return vm.opcode_counts().get(mnemonic).copied().unwrap_or(0);
+        };
+        // 4 + 5 * 2 is folded, a * 3 is left
+        assert_eq!((count(none(), "MUL"), count(PipelineOptions { fold_constants: true, ..none() }, "MUL")), (2, 1));
+        // a + 1 is removed, 4 + 5 * 2 isn't folded
+        assert_eq!((count(none(), "ADD"), count(PipelineOptions { dead_code: true, ..none() }, "ADD")), (2, 1));
+        // The second LOAD of 7 is removed
+        assert_eq!(count(none(), "LOAD") - count(PipelineOptions { peephole: true, ..none() }, "LOAD"), 1);
+        assert_eq!(run_source(source, none()).unwrap().register(1), Some(42));
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_stage() {
+        let mut diagnostics = Diagnostics::new();
+        assert!(run_source_with_diagnostics("let a = 4 $ 5", PipelineOptions::default(), &mut diagnostics).is_none());
+        let diagnostic = &diagnostics.diagnostics()[0];
+        assert_eq!((diagnostic.stage, diagnostic.line, diagnostic.col), (Stage::Lex, Some(1), Some(11)));
+
+        let mut diagnostics = Diagnostics::new();
+        assert!(run_source_with_diagnostics("let a = 0;\nprint(5 / a)", PipelineOptions::default(), &mut diagnostics).is_none());
+        assert_eq!(diagnostics.diagnostics().len(), 1);
+        let diagnostic = &diagnostics.diagnostics()[0];
+        assert_eq!((diagnostic.stage, diagnostic.line, diagnostic.col), (Stage::Run, Some(2), Some(1)));
+
+        let mut diagnostics = Diagnostics::new();
+        assert!(run_source_with_diagnostics("print(1)", PipelineOptions::default(), &mut diagnostics).is_some());
+        assert!(diagnostics.is_empty());
+    }
+
+}
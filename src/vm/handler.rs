@@ -0,0 +1,170 @@
+/*
+Goal: Let users add instructions to the VM without modifying it.
+    - A handler is registered for an opcode on the VM
+    - CUSTOM 0xXX rA rB rC runs the handler of the opcode 0xXX
+    - The handler only gets access to the registers, the stack and the program counter through VmState
+ */
+
+use crate::vm::machine::{VmError, Register};
+
+pub trait InstructionHandler {
+    fn opcode(&self) -> u8;
+    fn execute(&self, state: &mut VmState) -> Result<(), VmError>;
+}
+
+pub struct VmState<'a> {
+    pub args: [Register; 3],    // Registers given to the CUSTOM instruction
+    registers: &'a mut [u8],
+    stack: &'a mut [u8],
+    writes: &'a mut Vec<(usize, u8)>,  // (address, previous value) of each write, read by VM::run_diff
+    read_only: &'a [(usize, usize)],
+    program_counter: usize,
+    jump: Option<usize>,
+}
+
+impl<'a> VmState<'a> {
+
+    pub fn new(args: [Register; 3], registers: &'a mut [u8], stack: &'a mut [u8], writes: &'a mut Vec<(usize, u8)>, read_only: &'a [(usize, usize)], program_counter: usize) -> VmState<'a> {
+        return VmState {
+            args,
+            registers,
+            stack,
+            writes,
+            read_only,
+            program_counter,
+            jump: None,
+        }
+    }
+
+    // A register that doesn't exist is an error, the VM stops with it like with any fault
+    pub fn register(&self, reg: Register) -> Result<u8, VmError> {
+        return match self.registers.get(reg as usize) {
+            Some(value) => Ok(*value),
+            None => Err(VmError::InvalidRegister(reg)),
+        }
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: u8) -> Result<(), VmError> {
+        match self.registers.get_mut(reg as usize) {
+            Some(register) => *register = value,
+            None => return Err(VmError::InvalidRegister(reg)),
+        }
+        return Ok(());
+    }
+
+    pub fn read(&self, address: u16) -> Result<u8, VmError> {
+        return match self.stack.get(address as usize) {
+            Some(value) => Ok(*value),
+            None => Err(VmError::InvalidMemoryAddress(address as usize)),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) -> Result<(), VmError> {
+        let address = address as usize;
+        if address >= self.stack.len() {
+            return Err(VmError::InvalidMemoryAddress(address));
+        }
+        if self.read_only.iter().any(|(ptr, size)| address >= *ptr && address < ptr + size) {
+            return Err(VmError::WriteToReadOnly { addr: address });
+        }
+        self.writes.push((address, self.stack[address]));
+        self.stack[address] = value;
+        return Ok(());
+    }
+
+    pub fn program_counter(&self) -> usize {
+        return self.program_counter;
+    }
+
+    // Continue at [address] instead of the next instruction
+    pub fn jump(&mut self, address: usize) {
+        self.jump = Some(address);
+    }
+
+    pub fn jumped(&self) -> Option<usize> {
+        return self.jump;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::instruction::Instruction;
+    use crate::vm::machine::{VM, HaltReason};
+
+    // [arg0] = integer square root of [arg1]
+    struct Sqrt;
+
+    impl InstructionHandler for Sqrt {
+        fn opcode(&self) -> u8 {
+            return 0x01;
+        }
+
+        fn execute(&self, state: &mut VmState) -> Result<(), VmError> {
+            let value = state.register(state.args[1])?;
+            let mut root = 0;
+            while (root + 1) * (root + 1) <= value as u16 {
+                root += 1;
+            }
+            return state.set_register(state.args[0], root as u8);
+        }
+    }
+
+    // Reads a register that doesn't exist
+    struct Broken;
+
+    impl InstructionHandler for Broken {
+        fn opcode(&self) -> u8 {
+            return 0x02;
+        }
+
+        fn execute(&self, state: &mut VmState) -> Result<(), VmError> {
+            state.register(200)?;
+            return Ok(());
+        }
+    }
+
+    // Writes [arg2] at the addresses [arg0] and [arg1], then 0 at [arg0]
+    struct Scatter;
+
+    impl InstructionHandler for Scatter {
+        fn opcode(&self) -> u8 {
+            return 0x03;
+        }
+
+        fn execute(&self, state: &mut VmState) -> Result<(), VmError> {
+            let value = state.register(state.args[2])?;
+            state.write(state.register(state.args[0])? as u16, value)?;
+            state.write(state.register(state.args[1])? as u16, value)?;
+            return state.write(state.register(state.args[0])? as u16, 0);
+        }
+    }
+
+    #[test]
+    fn custom_handler_runs_with_builtin_instructions() {
+        let mut vm = VM::new(vec![Instruction::Load(1, 200), Instruction::Custom(0x01, 0, 1, 0), Instruction::Load(2, 1), Instruction::Add(0, 0, 2)]);
+        vm.add_handler(Box::new(Sqrt));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!(vm.register(0), Some(15));
+    }
+
+    #[test]
+    fn handler_reading_a_missing_register_faults() {
+        let mut vm = VM::new(vec![Instruction::Load(0, 1), Instruction::Custom(0x02, 0, 0, 0), Instruction::Load(0, 2)]);
+        vm.add_handler(Box::new(Broken));
+        assert_eq!(vm.run(), HaltReason::Fault(VmError::InvalidRegister(200)));
+        assert_eq!((vm.program_counter(), vm.register(0)), (1, Some(1)));
+    }
+
+    #[test]
+    fn handler_writes_are_in_the_diff() {
+        let mut vm = VM::new(vec![Instruction::Load(0, 4), Instruction::Load(1, 2), Instruction::Load(2, 8), Instruction::Custom(0x03, 0, 1, 2)]);
+        vm.add_handler(Box::new(Scatter));
+        let (reason, steps) = vm.run_diff();
+        assert_eq!(reason, HaltReason::Finished);
+        // 0x0004 is back to its first value
+        assert_eq!(format!("{:?}", steps[3].changes), "[[0x0002]: 0x00 -> 0x08]");
+    }
+
+}
@@ -1,59 +1,373 @@
-use crate::vm::machine::{Register, Byte, REGISTERS, IGNORE};
+use crate::vm::machine::{Register, Byte, IGNORE};
+use crate::vm::opcode;
 use std::fmt::{Debug, Formatter};
 
+#[derive(Clone, PartialEq)]
 pub enum Instruction {
     // Register Operation
     Load(Register, Byte),                   // Load the value [arg1] to the register [arg0]
+    Mov(Register, Register),                // Copy the register [arg1] to the register [arg0]
     Add(Register, Register, Register),      // Add the registers [arg1] and [arg2] and put the result in register [arg0]
     Sub(Register, Register, Register),      // Subtract the registers [arg1] and [arg2] and put the result in register [arg0]
     Mul(Register, Register, Register),      // Multiply the registers [arg1] and [arg2] and put the result in register [arg0]
-    Div(Register, Register, Register),      // Divide the registers [arg1] and [arg2] and put the result in register [arg0]
+    Div(Register, Register, Register),      // Divide the registers [arg1] and [arg2] and put the result in register [arg0] (dividing by 0 faults, or gives 0 and sets the error flag with DivZeroMode::YieldZero)
+    Mod(Register, Register, Register),      // Put the remainder of the division of the registers [arg1] and [arg2] in register [arg0] (same division by 0 behavior as DIV)
     Cmp(Register, Register, Register),      // Compare the registers [arg1] and [arg2] and put the result in register [arg0] (0 -> [arg1] < [arg2], 1 -> [arg1] == [arg2], 2 -> [arg1] > [arg2])
+    CmpF(Register, Register),               // Compare the registers [arg0] and [arg1] like CMP but only set the flags
+    MulWide(Register, Register, Register, Register), // Multiply the registers [arg2] and [arg3] and put the 16 bits result in registers [arg0][arg1]
+    FMul(Register, Register, Register),     // Multiply the Q4.4 fixed-point registers [arg1] and [arg2] and put the result in register [arg0] (saturates on overflow)
+    Min(Register, Register, Register),      // Put the smallest (unsigned) of the registers [arg1] and [arg2] in register [arg0]
+    Max(Register, Register, Register),      // Put the largest (unsigned) of the registers [arg1] and [arg2] in register [arg0]
+    And(Register, Register, Register),      // Bitwise and of the registers [arg1] and [arg2] put in register [arg0]
+    Or(Register, Register, Register),       // Bitwise or of the registers [arg1] and [arg2] put in register [arg0]
+    Xor(Register, Register, Register),      // Bitwise xor of the registers [arg1] and [arg2] put in register [arg0]
+    Not(Register, Register),                // Bitwise not of the register [arg1] put in register [arg0]
+    Shl(Register, Register, Register),      // Shift the register [arg1] left by the register [arg2] and put the result in register [arg0] (the shift amount is taken modulo 8)
+    Shr(Register, Register, Register),      // Shift the register [arg1] right by the register [arg2] and put the result in register [arg0] (the shift amount is taken modulo 8)
+    CMov(Register, Register, Register),     // Copy the register [arg1] to the register [arg0] if the register [arg2] isn't 0
     // Stack Operation
     SPush(Register, Register, Register),    // Push the register [arg2] to the stack and put the address in [arg0][arg1]
     SCopy(Register, Register, Register),    // Copy the value at address [arg0][arg1] and put it in the register [arg2]
     SPop(Register, Register, Register),     // Pop the value at address [arg0][arg1] and put it in the register [arg2]
     SRep(Register, Register, Register),     // Replace the value at address [arg0][arg1] byt the register [arg2]
+    LoadInd(Register, Register, Register),  // Load the value at address [arg0][arg1] to the register [arg2]
+    StoreInd(Register, Register, Register), // Store the register [arg2] at address [arg0][arg1]
     // Flow Control
     REq(Register, Register),                // Skip the next instruction if the register [arg0] != to the register [arg1]
     Eq(Register, Byte),                     // Skip the next instruction if the register [arg0] != to the value [arg1]
+    Jump8(Byte),                            // Jump to the address [arg0] (first 256 instructions)
+    RJump8(Register),                       // Jump to the address stored in register [arg0] (first 256 instructions)
     Jump16(Byte, Byte),                     // Jump to the 16 bits address [arg0][arg1]
     RJump16(Register, Register),            // Jump to the 16 bits address stored in registers [arg0][arg1]
+    JzReg(Register, Byte, Byte),            // Jump to the 16 bits address [arg1][arg2] if the register [arg0] is 0
+    JnzReg(Register, Byte, Byte),           // Jump to the 16 bits address [arg1][arg2] if the register [arg0] isn't 0
+    Jz(Byte, Byte),                         // Jump to the 16 bits address [arg0][arg1] if the zero flag is set
+    Jnz(Byte, Byte),                        // Jump to the 16 bits address [arg0][arg1] if the zero flag isn't set
+    Jc(Byte, Byte),                         // Jump to the 16 bits address [arg0][arg1] if the carry flag is set
     Halt(),                                 // Pause the program (Usually End of Program)
+    // Input/Output
+    Out(Register),                          // Write the register [arg0] to the output of the VM
+    In(Register),                           // Read a byte from the input of the VM to the register [arg0] (0 once the input has ended)
+    // Debug
+    Trap(),                                 // Print the registers and the memory map then continue
+    // Extension
+    Custom(Byte, Register, Register, Register), // Run the handler added to the VM for the opcode [arg0] with the registers [arg1] [arg2] [arg3]
 }
 
 impl Debug for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Instruction::Load(a, b) => write!(f, "LOAD r{:X} 0x{:02X}", a, b)?,
+            Instruction::Mov(a, b) => write!(f, "MOV r{:X} r{:X}", a, b)?,
             Instruction::Add(a, b, c) => write!(f, "ADD r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::Sub(a, b, c) => write!(f, "SUB r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::Mul(a, b, c) => write!(f, "MUL r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::Div(a, b, c) => write!(f, "DIV r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Mod(a, b, c) => write!(f, "MOD r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::Cmp(a, b, c) => write!(f, "CMP r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::CmpF(a, b) => write!(f, "CMPF r{:X} r{:X}", a, b)?,
+            Instruction::MulWide(a, b, c, d) => write!(f, "MULW r{:X} r{:X} r{:X} r{:X}", a, b, c, d)?,
+            Instruction::FMul(a, b, c) => write!(f, "FMUL r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Min(a, b, c) => write!(f, "MIN r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Max(a, b, c) => write!(f, "MAX r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::And(a, b, c) => write!(f, "AND r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Or(a, b, c) => write!(f, "OR r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Xor(a, b, c) => write!(f, "XOR r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Not(a, b) => write!(f, "NOT r{:X} r{:X}", a, b)?,
+            Instruction::Shl(a, b, c) => write!(f, "SHL r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::Shr(a, b, c) => write!(f, "SHR r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::CMov(a, b, c) => write!(f, "CMOV r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::SPush(a, b, c) => {
                 write!(f, "SPUSH ")?;
-                if *a >= IGNORE {
+                if *a == IGNORE {
                     write!(f, "_ ")?;
                 } else {
-                    write!(f, "r{:X}", a)?;
+                    write!(f, "r{:X} ", a)?;
                 }
-                if *b >= IGNORE {
+                if *b == IGNORE {
                     write!(f, "_ ")?;
                 } else {
-                    write!(f, "r{:X}", b)?;
+                    write!(f, "r{:X} ", b)?;
                 }
                 write!(f, "r{:X}", c)?;
             },
             Instruction::SCopy(a, b, c) => write!(f, "SCOPY r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::SPop(a, b, c) => write!(f, "SPOP r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::SRep(a, b, c) => write!(f, "SREP r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::LoadInd(a, b, c) => write!(f, "LDI r{:X} r{:X} r{:X}", a, b, c)?,
+            Instruction::StoreInd(a, b, c) => write!(f, "STI r{:X} r{:X} r{:X}", a, b, c)?,
             Instruction::REq(a, b) => write!(f, "REQ r{:X} r{:X}", a, b)?,
-            Instruction::Eq(a, b) => write!(f, "REQ r{:X} 0x{:02X}", a, b)?,
+            Instruction::Eq(a, b) => write!(f, "EQ r{:X} 0x{:02X}", a, b)?,
+            Instruction::Jump8(a) => write!(f, "JUMP8 0x{:02X}", a)?,
+            Instruction::RJump8(a) => write!(f, "RJUMP8 r{:X}", a)?,
             Instruction::Jump16(a, b) => write!(f, "JUMP16 0x{:02X} 0x{:02X}", a, b)?,
             Instruction::RJump16(a, b) => write!(f, "RJUMP16 r{:X} r{:X}", a, b)?,
+            Instruction::JzReg(a, b, c) => write!(f, "JZR r{:X} 0x{:02X} 0x{:02X}", a, b, c)?,
+            Instruction::JnzReg(a, b, c) => write!(f, "JNZR r{:X} 0x{:02X} 0x{:02X}", a, b, c)?,
+            Instruction::Jz(a, b) => write!(f, "JZ 0x{:02X} 0x{:02X}", a, b)?,
+            Instruction::Jnz(a, b) => write!(f, "JNZ 0x{:02X} 0x{:02X}", a, b)?,
+            Instruction::Jc(a, b) => write!(f, "JC 0x{:02X} 0x{:02X}", a, b)?,
             Instruction::Halt() => write!(f, "HALT")?,
+            Instruction::Out(a) => write!(f, "OUT r{:X}", a)?,
+            Instruction::In(a) => write!(f, "IN r{:X}", a)?,
+            Instruction::Trap() => write!(f, "TRAP")?,
+            Instruction::Custom(a, b, c, d) => write!(f, "CUSTOM 0x{:02X} r{:X} r{:X} r{:X}", a, b, c, d)?,
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Instruction {
+
+    // Name of the instruction in the assembler
+    pub fn mnemonic(&self) -> &'static str {
+        return match self {
+            Instruction::Load(_, _) => "LOAD",
+            Instruction::Mov(_, _) => "MOV",
+            Instruction::Add(_, _, _) => "ADD",
+            Instruction::Sub(_, _, _) => "SUB",
+            Instruction::Mul(_, _, _) => "MUL",
+            Instruction::Div(_, _, _) => "DIV",
+            Instruction::Mod(_, _, _) => "MOD",
+            Instruction::Cmp(_, _, _) => "CMP",
+            Instruction::CmpF(_, _) => "CMPF",
+            Instruction::MulWide(_, _, _, _) => "MULW",
+            Instruction::FMul(_, _, _) => "FMUL",
+            Instruction::Min(_, _, _) => "MIN",
+            Instruction::Max(_, _, _) => "MAX",
+            Instruction::And(_, _, _) => "AND",
+            Instruction::Or(_, _, _) => "OR",
+            Instruction::Xor(_, _, _) => "XOR",
+            Instruction::Not(_, _) => "NOT",
+            Instruction::Shl(_, _, _) => "SHL",
+            Instruction::Shr(_, _, _) => "SHR",
+            Instruction::CMov(_, _, _) => "CMOV",
+            Instruction::SPush(_, _, _) => "SPUSH",
+            Instruction::SCopy(_, _, _) => "SCOPY",
+            Instruction::SPop(_, _, _) => "SPOP",
+            Instruction::SRep(_, _, _) => "SREP",
+            Instruction::LoadInd(_, _, _) => "LDI",
+            Instruction::StoreInd(_, _, _) => "STI",
+            Instruction::REq(_, _) => "REQ",
+            Instruction::Eq(_, _) => "EQ",
+            Instruction::Jump8(_) => "JUMP8",
+            Instruction::RJump8(_) => "RJUMP8",
+            Instruction::Jump16(_, _) => "JUMP16",
+            Instruction::RJump16(_, _) => "RJUMP16",
+            Instruction::JzReg(_, _, _) => "JZR",
+            Instruction::JnzReg(_, _, _) => "JNZR",
+            Instruction::Jz(_, _) => "JZ",
+            Instruction::Jnz(_, _) => "JNZ",
+            Instruction::Jc(_, _) => "JC",
+            Instruction::Halt() => "HALT",
+            Instruction::Out(_) => "OUT",
+            Instruction::In(_) => "IN",
+            Instruction::Trap() => "TRAP",
+            Instruction::Custom(_, _, _, _) => "CUSTOM",
+        }
+    }
+
+    // Opcode followed by the arguments in order
+    pub fn to_bytes(&self) -> Vec<u8> {
+        return match *self {
+            Instruction::Load(a, b) => vec![opcode::LOAD, a, b],
+            Instruction::Mov(a, b) => vec![opcode::MOV, a, b],
+            Instruction::Add(a, b, c) => vec![opcode::ADD, a, b, c],
+            Instruction::Sub(a, b, c) => vec![opcode::SUB, a, b, c],
+            Instruction::Mul(a, b, c) => vec![opcode::MUL, a, b, c],
+            Instruction::Div(a, b, c) => vec![opcode::DIV, a, b, c],
+            Instruction::Mod(a, b, c) => vec![opcode::MOD, a, b, c],
+            Instruction::Cmp(a, b, c) => vec![opcode::CMP, a, b, c],
+            Instruction::CmpF(a, b) => vec![opcode::CMPF, a, b],
+            Instruction::MulWide(a, b, c, d) => vec![opcode::MULW, a, b, c, d],
+            Instruction::FMul(a, b, c) => vec![opcode::FMUL, a, b, c],
+            Instruction::Min(a, b, c) => vec![opcode::MIN, a, b, c],
+            Instruction::Max(a, b, c) => vec![opcode::MAX, a, b, c],
+            Instruction::And(a, b, c) => vec![opcode::AND, a, b, c],
+            Instruction::Or(a, b, c) => vec![opcode::OR, a, b, c],
+            Instruction::Xor(a, b, c) => vec![opcode::XOR, a, b, c],
+            Instruction::Not(a, b) => vec![opcode::NOT, a, b],
+            Instruction::Shl(a, b, c) => vec![opcode::SHL, a, b, c],
+            Instruction::Shr(a, b, c) => vec![opcode::SHR, a, b, c],
+            Instruction::CMov(a, b, c) => vec![opcode::CMOV, a, b, c],
+            Instruction::SPush(a, b, c) => vec![opcode::SPUSH, a, b, c],
+            Instruction::SCopy(a, b, c) => vec![opcode::SCOPY, a, b, c],
+            Instruction::SPop(a, b, c) => vec![opcode::SPOP, a, b, c],
+            Instruction::SRep(a, b, c) => vec![opcode::SREP, a, b, c],
+            Instruction::LoadInd(a, b, c) => vec![opcode::LDI, a, b, c],
+            Instruction::StoreInd(a, b, c) => vec![opcode::STI, a, b, c],
+            Instruction::REq(a, b) => vec![opcode::REQ, a, b],
+            Instruction::Eq(a, b) => vec![opcode::EQ, a, b],
+            Instruction::Jump8(a) => vec![opcode::JUMP8, a],
+            Instruction::RJump8(a) => vec![opcode::RJUMP8, a],
+            Instruction::Jump16(a, b) => vec![opcode::JUMP16, a, b],
+            Instruction::RJump16(a, b) => vec![opcode::RJUMP16, a, b],
+            Instruction::JzReg(a, b, c) => vec![opcode::JZR, a, b, c],
+            Instruction::JnzReg(a, b, c) => vec![opcode::JNZR, a, b, c],
+            Instruction::Jz(a, b) => vec![opcode::JZ, a, b],
+            Instruction::Jnz(a, b) => vec![opcode::JNZ, a, b],
+            Instruction::Jc(a, b) => vec![opcode::JC, a, b],
+            Instruction::Halt() => vec![opcode::HALT],
+            Instruction::Out(a) => vec![opcode::OUT, a],
+            Instruction::In(a) => vec![opcode::IN, a],
+            Instruction::Trap() => vec![opcode::TRAP],
+            Instruction::Custom(a, b, c, d) => vec![opcode::CUSTOM, a, b, c, d],
+        }
+    }
+
+}
+
+pub fn program_to_bytes(program: &[Instruction]) -> Vec<u8> {
+    return program.iter().flat_map(|instruction| instruction.to_bytes()).collect();
+}
+
+pub enum DecodeError {
+    UnknownOpcode { opcode: u8, offset: usize },
+    Truncated { opcode: u8, offset: usize },    // The bytes end before all the arguments of the instruction
+}
+
+impl Debug for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode { opcode, offset } => write!(f, "Unknown Opcode: 0x{:02X} at byte {}", opcode, offset)?,
+            DecodeError::Truncated { opcode, offset } => write!(f, "Truncated Instruction: opcode 0x{:02X} at byte {} is missing arguments", opcode, offset)?,
+        }
+        Ok(())
+    }
+}
+
+pub fn program_from_bytes(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    let mut program = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let op = bytes[offset];
+        let count = match opcode::operand_count(op) {
+            Some(count) => count,
+            None => return Err(DecodeError::UnknownOpcode { opcode: op, offset }),
+        };
+        let a = match bytes.get((offset + 1)..(offset + 1 + count)) {
+            Some(args) => args,
+            None => return Err(DecodeError::Truncated { opcode: op, offset }),
+        };
+        let instruction = match op {
+            opcode::LOAD => Instruction::Load(a[0], a[1]),
+            opcode::MOV => Instruction::Mov(a[0], a[1]),
+            opcode::ADD => Instruction::Add(a[0], a[1], a[2]),
+            opcode::SUB => Instruction::Sub(a[0], a[1], a[2]),
+            opcode::MUL => Instruction::Mul(a[0], a[1], a[2]),
+            opcode::DIV => Instruction::Div(a[0], a[1], a[2]),
+            opcode::MOD => Instruction::Mod(a[0], a[1], a[2]),
+            opcode::CMP => Instruction::Cmp(a[0], a[1], a[2]),
+            opcode::CMPF => Instruction::CmpF(a[0], a[1]),
+            opcode::MULW => Instruction::MulWide(a[0], a[1], a[2], a[3]),
+            opcode::FMUL => Instruction::FMul(a[0], a[1], a[2]),
+            opcode::MIN => Instruction::Min(a[0], a[1], a[2]),
+            opcode::MAX => Instruction::Max(a[0], a[1], a[2]),
+            opcode::AND => Instruction::And(a[0], a[1], a[2]),
+            opcode::OR => Instruction::Or(a[0], a[1], a[2]),
+            opcode::XOR => Instruction::Xor(a[0], a[1], a[2]),
+            opcode::NOT => Instruction::Not(a[0], a[1]),
+            opcode::SHL => Instruction::Shl(a[0], a[1], a[2]),
+            opcode::SHR => Instruction::Shr(a[0], a[1], a[2]),
+            opcode::CMOV => Instruction::CMov(a[0], a[1], a[2]),
+            opcode::SPUSH => Instruction::SPush(a[0], a[1], a[2]),
+            opcode::SCOPY => Instruction::SCopy(a[0], a[1], a[2]),
+            opcode::SPOP => Instruction::SPop(a[0], a[1], a[2]),
+            opcode::SREP => Instruction::SRep(a[0], a[1], a[2]),
+            opcode::LDI => Instruction::LoadInd(a[0], a[1], a[2]),
+            opcode::STI => Instruction::StoreInd(a[0], a[1], a[2]),
+            opcode::REQ => Instruction::REq(a[0], a[1]),
+            opcode::EQ => Instruction::Eq(a[0], a[1]),
+            opcode::JUMP8 => Instruction::Jump8(a[0]),
+            opcode::RJUMP8 => Instruction::RJump8(a[0]),
+            opcode::JUMP16 => Instruction::Jump16(a[0], a[1]),
+            opcode::RJUMP16 => Instruction::RJump16(a[0], a[1]),
+            opcode::JZR => Instruction::JzReg(a[0], a[1], a[2]),
+            opcode::JNZR => Instruction::JnzReg(a[0], a[1], a[2]),
+            opcode::JZ => Instruction::Jz(a[0], a[1]),
+            opcode::JNZ => Instruction::Jnz(a[0], a[1]),
+            opcode::JC => Instruction::Jc(a[0], a[1]),
+            opcode::HALT => Instruction::Halt(),
+            opcode::OUT => Instruction::Out(a[0]),
+            opcode::IN => Instruction::In(a[0]),
+            opcode::TRAP => Instruction::Trap(),
+            opcode::CUSTOM => Instruction::Custom(a[0], a[1], a[2], a[3]),
+            _ => return Err(DecodeError::UnknownOpcode { opcode: op, offset }),
+        };
+        program.push(instruction);
+        offset += 1 + count;
+    }
+    return Ok(program);
+}
+
+// FNV-1a hash of the bytecode, only depends on the instructions so equal programs always hash equally
+pub fn program_hash(program: &[Instruction]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+    for byte in program_to_bytes(program) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+    return hash;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eq_is_printed_as_eq() {
+        assert_eq!(format!("{:?}", Instruction::Eq(1, 5)), "EQ r1 0x05");
+    }
+
+    #[test]
+    fn program_is_encoded_as_opcodes_and_arguments() {
+        let program = vec![Instruction::Load(0, 0x2A), Instruction::Add(2, 0, 1), Instruction::Jump16(0x01, 0x02), Instruction::Out(2), Instruction::Halt()];
+        assert_eq!(program_to_bytes(&program), vec![
+            opcode::LOAD, 0x00, 0x2A,
+            opcode::ADD, 0x02, 0x00, 0x01,
+            opcode::JUMP16, 0x01, 0x02,
+            opcode::OUT, 0x02,
+            opcode::HALT,
+        ]);
+        assert_eq!(program_to_bytes(&program)[..4], [0x00, 0x00, 0x2A, 0x02]);
+    }
+
+    // One of each variant, the arguments are different so a swap is noticed
+    fn every_instruction() -> Vec<Instruction> {
+        return vec![
+            Instruction::Load(1, 2), Instruction::Mov(1, 2), Instruction::Add(1, 2, 3), Instruction::Sub(1, 2, 3),
+            Instruction::Mul(1, 2, 3), Instruction::Div(1, 2, 3), Instruction::Mod(1, 2, 3), Instruction::Cmp(1, 2, 3),
+            Instruction::CmpF(1, 2), Instruction::MulWide(1, 2, 3, 4), Instruction::FMul(1, 2, 3), Instruction::Min(1, 2, 3),
+            Instruction::Max(1, 2, 3), Instruction::And(1, 2, 3), Instruction::Or(1, 2, 3), Instruction::Xor(1, 2, 3),
+            Instruction::Not(1, 2), Instruction::Shl(1, 2, 3), Instruction::Shr(1, 2, 3), Instruction::CMov(1, 2, 3),
+            Instruction::SPush(IGNORE, 2, 3), Instruction::SCopy(1, 2, 3), Instruction::SPop(1, 2, 3), Instruction::SRep(1, 2, 3),
+            Instruction::LoadInd(1, 2, 3), Instruction::StoreInd(1, 2, 3), Instruction::REq(1, 2), Instruction::Eq(1, 2),
+            Instruction::Jump8(1), Instruction::RJump8(1), Instruction::Jump16(1, 2), Instruction::RJump16(1, 2),
+            Instruction::JzReg(1, 2, 3), Instruction::JnzReg(1, 2, 3), Instruction::Jz(1, 2), Instruction::Jnz(1, 2),
+            Instruction::Jc(1, 2), Instruction::Halt(), Instruction::Out(1), Instruction::In(1),
+            Instruction::Trap(), Instruction::Custom(1, 2, 3, 4),
+        ];
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        let program = every_instruction();
+        let mut mnemonics: Vec<&str> = program.iter().map(|instruction| instruction.mnemonic()).collect();
+        mnemonics.sort();
+        mnemonics.dedup();
+        assert_eq!(mnemonics.len(), program.len());
+        for instruction in program.iter() {
+            let bytes = instruction.to_bytes();
+            assert_eq!(opcode::operand_count(bytes[0]), Some(bytes.len() - 1), "{:?}", instruction);
+        }
+        assert_eq!(program_from_bytes(&program_to_bytes(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn bad_bytecode_is_a_decode_error() {
+        assert!(matches!(program_from_bytes(&[opcode::HALT, 0xFF]), Err(DecodeError::UnknownOpcode { opcode: 0xFF, offset: 1 })));
+        // LOAD needs 2 arguments
+        assert!(matches!(program_from_bytes(&[opcode::OUT, 0x00, opcode::LOAD, 0x00]), Err(DecodeError::Truncated { opcode: opcode::LOAD, offset: 2 })));
+        assert!(matches!(program_from_bytes(&[]), Ok(program) if program.is_empty()));
+    }
+
+}
@@ -1,5 +1,10 @@
 use crate::vm::instruction::Instruction;
-use std::cmp::min;
+use crate::vm::handler::{InstructionHandler, VmState};
+use crate::diagnostic::Span;
+use std::cmp::{min, max};
+use std::fmt::{Debug, Formatter};
+use std::collections::HashMap;
+use std::io::{Read, Write, ErrorKind};
 
 /*
 Structure:
@@ -16,48 +21,479 @@ pub type Byte = u8;
 pub const STACK_SIZE: usize = 2_usize.pow(16); // 2^16 Byte of memory (max sized allowed due to 16bit address)
 pub const REGISTERS: usize = 16;
 
-pub const IGNORE: u8 = REGISTERS as u8;
+// Register argument meaning "don't write the value" (SPUSH address), out of range for any register count a program can use
+pub const IGNORE: u8 = u8::MAX;
+
+// Bits of the flags register
+// Set by every arithmetic, bitwise, shift and compare instruction, the others keep them
+pub const FLAG_ZERO: u8 = 0b001;    // The result is 0 (both bytes for MULW)
+pub const FLAG_CARRY: u8 = 0b010;   // The unsigned result didn't fit in a byte (borrow for SUB and CMP, high byte used for MULW, saturated FMUL, 1 shifted out for SHL/SHR, never for MIN/MAX/AND/OR/XOR/NOT)
+pub const FLAG_SIGN: u8 = 0b100;    // The high bit of the result is set (of the high byte for MULW)
+
+#[derive(PartialEq)]
+pub enum VmError {
+    InvalidProgramAddress(usize),
+    WriteToReadOnly { addr: usize },
+    PcOutOfRange { pc: usize },
+    UnknownOpcode(u8),
+    DivByZero { pc: usize },
+    InvalidRegister(u8),
+    Overflow { pc: usize },
+    InvalidMemoryAddress(usize),
+    StackFull,
+    Io(ErrorKind),
+    Located { error: Box<VmError>, span: Span }, // Error of an instruction whose source position is known (set_spans)
+}
+
+impl Debug for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::InvalidProgramAddress(addr) => write!(f, "Invalid Program Address: 0x{:04X}", addr)?,
+            VmError::WriteToReadOnly { addr } => write!(f, "Write To Read Only Memory: 0x{:04X}", addr)?,
+            VmError::PcOutOfRange { pc } => write!(f, "Program Counter Out Of Range: 0x{:04X}", pc)?,
+            VmError::UnknownOpcode(opcode) => write!(f, "Unknown Opcode: 0x{:02X} has no handler", opcode)?,
+            VmError::DivByZero { pc } => write!(f, "Division By Zero at 0x{:04X}", pc)?,
+            VmError::Io(kind) => write!(f, "IO Error: {:?}", kind)?,
+            VmError::StackFull => write!(f, "Stack Full: no free memory for SPUSH")?,
+            VmError::InvalidMemoryAddress(addr) => write!(f, "Invalid Memory Address: 0x{:X}", addr)?,
+            VmError::Overflow { pc } => write!(f, "Arithmetic Overflow at 0x{:04X}", pc)?,
+            VmError::InvalidRegister(reg) => write!(f, "Invalid Register: r{:X}", reg)?,
+            VmError::Located { error, span } => write!(f, "{:?} (line {})", error, span.line)?,
+        }
+        Ok(())
+    }
+}
+
+// Registers used by the instruction, the SPUSH address registers can be IGNORE so they are left out when they are
+pub(crate) fn register_operands(instruction: &Instruction) -> Vec<Register> {
+    return match *instruction {
+        Instruction::Load(a, _) | Instruction::Eq(a, _) | Instruction::RJump8(a) | Instruction::Out(a) | Instruction::In(a) | Instruction::JzReg(a, _, _) | Instruction::JnzReg(a, _, _) => vec![a],
+        Instruction::Add(a, b, c) | Instruction::Sub(a, b, c) | Instruction::Mul(a, b, c) | Instruction::Div(a, b, c) | Instruction::Mod(a, b, c) |
+        Instruction::Cmp(a, b, c) | Instruction::FMul(a, b, c) | Instruction::Min(a, b, c) | Instruction::Max(a, b, c) |
+        Instruction::And(a, b, c) | Instruction::Or(a, b, c) | Instruction::Xor(a, b, c) |
+        Instruction::Shl(a, b, c) | Instruction::Shr(a, b, c) |
+        Instruction::CMov(a, b, c) | Instruction::SCopy(a, b, c) | Instruction::SPop(a, b, c) | Instruction::SRep(a, b, c) |
+        Instruction::LoadInd(a, b, c) | Instruction::StoreInd(a, b, c) | Instruction::Custom(_, a, b, c) => vec![a, b, c],
+        Instruction::MulWide(a, b, c, d) => vec![a, b, c, d],
+        Instruction::SPush(a, b, c) => {
+            let mut registers = vec![c];
+            if a != IGNORE {
+                registers.push(a);
+            }
+            if b != IGNORE {
+                registers.push(b);
+            }
+            registers
+        }
+        Instruction::Mov(a, b) | Instruction::CmpF(a, b) | Instruction::REq(a, b) | Instruction::RJump16(a, b) | Instruction::Not(a, b) => vec![a, b],
+        Instruction::Jump8(_) | Instruction::Jump16(_, _) | Instruction::Jz(_, _) | Instruction::Jnz(_, _) | Instruction::Jc(_, _) |
+        Instruction::Halt() | Instruction::Trap() => vec![],
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HaltReason {
+    Halted,     // Stopped on a HALT instruction
+    Finished,   // Ran past the last instruction without a HALT
+    Fault(VmError), // An instruction failed, the program counter is left on it
+    CycleLimit, // Executed the maximum number of instructions without stopping
+}
+
+// Behavior of ADD, SUB and MUL when the result doesn't fit in a byte, the default is Wrapping (modulo 256)
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArithmeticMode {
+    Wrapping,   // Keep the low 8 bits of the result
+    Checked,    // Stop the program with VmError::Overflow
+    Saturating, // Clamp the result to 0 or 255
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DivZeroMode {
+    Fault,      // Stop the program with VmError::DivByZero
+    YieldZero,  // Write 0 to the result register, set the error flag and continue
+}
+
+#[derive(Clone, Copy)]
+pub enum Radix {
+    Hex,
+    Dec,
+    Bin,
+}
+
+impl Default for Radix {
+    fn default() -> Self {
+        return Radix::Hex;
+    }
+}
+
+impl Radix {
+
+    fn prefix(&self) -> &'static str {
+        return match self {
+            Radix::Hex => "0x",
+            Radix::Dec => "",
+            Radix::Bin => "0b",
+        }
+    }
+
+    // Format a byte with a fixed width for the radix so columns stay aligned
+    fn format(&self, value: u8) -> String {
+        return match self {
+            Radix::Hex => format!("{:02X}", value),
+            Radix::Dec => format!("{:3}", value),
+            Radix::Bin => format!("{:08b}", value),
+        }
+    }
+
+}
+
+// A value modified by a single instruction (old -> new)
+pub enum Change {
+    Register(Register, u8, u8),
+    Memory(usize, u8, u8),
+}
+
+// What the instruction at [address] changed when it was executed
+pub struct StepDiff {
+    pub address: usize,
+    pub instruction: Instruction,
+    pub changes: Vec<Change>,
+}
+
+impl Debug for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Register(reg, old, new) => write!(f, "r{:X}: 0x{:02X} -> 0x{:02X}", reg, old, new)?,
+            Change::Memory(addr, old, new) => write!(f, "[0x{:04X}]: 0x{:02X} -> 0x{:02X}", addr, old, new)?,
+        }
+        Ok(())
+    }
+}
+
+impl Debug for StepDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:04X}] {:?}", self.address, self.instruction)?;
+        for change in self.changes.iter() {
+            write!(f, "\n    {:?}", change)?;
+        }
+        Ok(())
+    }
+}
+
+// Execution state of a VM, the program isn't included
+#[derive(Clone)]
+pub struct VmSnapshot {
+    registers: Vec<u8>,
+    stack: Vec<u8>,
+    stack_memory_map: Vec<(usize, usize)>,
+    program_counter: usize,
+    max_stack_used: usize,
+    error_flag: bool,
+    flags: u8,
+}
+
+pub struct VmConfig {
+    pub stack_size: usize,  // Bytes of memory, clamped to 1..=2^16 (addresses are 16 bits)
+    pub registers: usize,   // Number of registers, at most 255 (IGNORE isn't a register)
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        return VmConfig {
+            stack_size: STACK_SIZE,
+            registers: REGISTERS,
+        }
+    }
+}
 
 pub struct VM {
-    stack: [u8; STACK_SIZE],
+    stack: Vec<u8>,
     // (ptr, size)
     stack_memory_map: Vec<(usize, usize)>,
-    registers: [u8; REGISTERS],
+    registers: Vec<u8>,
     program: Vec<Instruction>,
     program_counter: usize,
+    max_stack_used: usize,      // Most bytes of the stack allocated at once
+    spans: Vec<Span>,           // Source position of each instruction (empty if unknown)
+    writes: Vec<(usize, u8)>,   // (address, previous value) of the memory written by the current instruction
+    initial_memory: (usize, Vec<u8>), // Data copied to the stack at [arg0] when the VM is created or reset
+    // (ptr, size)
+    read_only: Vec<(usize, usize)>,
+    handlers: Vec<Box<dyn InstructionHandler>>, // Run the CUSTOM instructions
+    arithmetic_mode: ArithmeticMode,
+    div_zero_mode: DivZeroMode,
+    error_flag: bool,           // Set when an instruction produced a wrong value instead of faulting
+    flags: u8,                  // FLAG_* bits set by the arithmetic, bitwise, shift and compare instructions
+    output: Box<dyn Write>,     // Written by OUT
+    trace: Box<dyn Write>,      // Written by TRAP
+    input: Box<dyn Read>,       // Read by IN
+    input_ended: bool,          // Set when IN reached the end of the input
+    cycles: u64,                // Instructions executed without a fault
+    opcode_counts: HashMap<&'static str, u64>,  // Instructions executed without a fault by mnemonic
 }
 
 impl VM {
 
     pub fn new(program: Vec<Instruction>) -> VM {
+        return VM::with_config(program, VmConfig::default());
+    }
+
+    pub fn with_config(program: Vec<Instruction>, config: VmConfig) -> VM {
+        let stack_size = config.stack_size.clamp(1, STACK_SIZE);
         return VM {
-            stack: [0; STACK_SIZE],
-            stack_memory_map: vec![(0, STACK_SIZE)],
-            registers: [0; REGISTERS],
+            stack: vec![0; stack_size],
+            stack_memory_map: vec![(0, stack_size)],
+            registers: vec![0; min(config.registers, IGNORE as usize)],
             program,
-            program_counter: 0
+            program_counter: 0,
+            max_stack_used: 0,
+            spans: vec![],
+            writes: vec![],
+            initial_memory: (0, vec![]),
+            read_only: vec![],
+            handlers: vec![],
+            arithmetic_mode: ArithmeticMode::Wrapping,
+            div_zero_mode: DivZeroMode::Fault,
+            error_flag: false,
+            flags: 0,
+            output: Box::new(std::io::stdout()),
+            trace: Box::new(std::io::stdout()),
+            input: Box::new(std::io::stdin()),
+            input_ended: false,
+            cycles: 0,
+            opcode_counts: HashMap::new(),
+        }
+    }
+
+    // Create a VM with [data] copied to the stack at [base], the region is marked as used so SPush won't overwrite it
+    // The data has to end before the end of the stack
+    pub fn with_initial_memory(program: Vec<Instruction>, data: &[u8], base: u16) -> Result<VM, VmError> {
+        let mut vm = VM::new(program);
+        let start = base as usize;
+        if start + data.len() > vm.stack.len() {
+            return Err(VmError::InvalidMemoryAddress(start + data.len() - 1));
+        }
+        vm.initial_memory = (start, data.to_vec());
+        vm.load_initial_memory();
+        return Ok(vm);
+    }
+
+    fn load_initial_memory(&mut self) {
+        let (start, size) = (self.initial_memory.0, self.initial_memory.1.len());
+        self.stack[start..(start + size)].copy_from_slice(&self.initial_memory.1);
+        self.reserve_memory(start, size);
+        self.max_stack_used = self.stack_used();
+    }
+
+    // Same as with_initial_memory but writing to or popping from the region is an error
+    pub fn with_read_only_memory(program: Vec<Instruction>, data: &[u8], base: u16) -> Result<VM, VmError> {
+        let mut vm = VM::with_initial_memory(program, data, base)?;
+        vm.read_only.push((base as usize, data.len()));
+        return Ok(vm);
+    }
+
+    // A handler added for an opcode that already has one replaces it
+    pub fn add_handler(&mut self, handler: Box<dyn InstructionHandler>) {
+        self.handlers.retain(|other| other.opcode() != handler.opcode());
+        self.handlers.push(handler);
+    }
+
+    pub fn with_arithmetic_mode(mut self, mode: ArithmeticMode) -> VM {
+        self.arithmetic_mode = mode;
+        return self;
+    }
+
+    // Apply the arithmetic mode to an operation given as its checked, wrapping and saturating versions
+    fn arithmetic(&self, a: u8, b: u8, checked: fn(u8, u8) -> Option<u8>, wrapping: fn(u8, u8) -> u8, saturating: fn(u8, u8) -> u8) -> Result<u8, VmError> {
+        return match self.arithmetic_mode {
+            ArithmeticMode::Wrapping => Ok(wrapping(a, b)),
+            ArithmeticMode::Saturating => Ok(saturating(a, b)),
+            ArithmeticMode::Checked => match checked(a, b) {
+                Some(result) => Ok(result),
+                None => Err(VmError::Overflow { pc: self.program_counter }),
+            },
+        }
+    }
+
+    pub fn set_div_zero_mode(&mut self, mode: DivZeroMode) {
+        self.div_zero_mode = mode;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn set_trace(&mut self, trace: Box<dyn Write>) {
+        self.trace = trace;
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.input = input;
+        self.input_ended = false;
+    }
+
+    pub fn input_ended(&self) -> bool {
+        return self.input_ended;
+    }
+
+    pub fn flags(&self) -> u8 {
+        return self.flags;
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        return self.flags & FLAG_ZERO != 0;
+    }
+
+    pub fn carry_flag(&self) -> bool {
+        return self.flags & FLAG_CARRY != 0;
+    }
+
+    pub fn sign_flag(&self) -> bool {
+        return self.flags & FLAG_SIGN != 0;
+    }
+
+    fn set_flags(&mut self, result: u8, carry: bool) {
+        self.flags = 0;
+        if result == 0 {
+            self.flags |= FLAG_ZERO;
+        }
+        if carry {
+            self.flags |= FLAG_CARRY;
+        }
+        if result & 0x80 != 0 {
+            self.flags |= FLAG_SIGN;
         }
     }
 
-    pub fn run_once(&mut self) -> bool {
+    pub fn error_flag(&self) -> bool {
+        return self.error_flag;
+    }
+
+    fn check_writable(&self, address: usize) -> Result<(), VmError> {
+        if self.read_only.iter().any(|(ptr, size)| address >= *ptr && address < ptr + size) {
+            return Err(VmError::WriteToReadOnly { addr: address });
+        }
+        return Ok(());
+    }
+
+    // Write to the stack, the previous value is kept for run_diff
+    fn write_memory(&mut self, address: usize, value: u8) {
+        self.writes.push((address, self.stack[address]));
+        self.stack[address] = value;
+    }
+
+    // Remove the region [start, start + size) from the available memory slots
+    fn reserve_memory(&mut self, start: usize, size: usize) {
+        let end = start + size;
+        let mut map = vec![];
+        for (ptr, len) in self.stack_memory_map.drain(..) {
+            if ptr + len <= start || ptr >= end {
+                map.push((ptr, len));
+                continue;
+            }
+            if ptr < start {
+                map.push((ptr, start - ptr));
+            }
+            if ptr + len > end {
+                map.push((end, ptr + len - end));
+            }
+        }
+        self.stack_memory_map = map;
+    }
+
+    // 16 bits address stored in the registers [reg1][reg2]
+    fn address(&self, reg1: Register, reg2: Register) -> usize {
+        return ((self.registers[reg1 as usize] as usize) << 8) | (self.registers[reg2 as usize] as usize);
+    }
+
+    // Same as address but the address has to be in the stack
+    fn stack_address(&self, reg1: Register, reg2: Register) -> Result<usize, VmError> {
+        let address = self.address(reg1, reg2);
+        if address >= self.stack.len() {
+            return Err(VmError::InvalidMemoryAddress(address));
+        }
+        return Ok(address);
+    }
+
+    // Jumping right after the last instruction ends the program like a fall-through, further is an error
+    fn check_jump(&self, target: usize) -> Result<usize, VmError> {
+        if target > self.program.len() {
+            return Err(VmError::PcOutOfRange { pc: target });
+        }
+        return Ok(target);
+    }
+
+    pub fn run_once(&mut self) -> Result<bool, VmError> {
         if self.program_counter >= self.program.len() {
-            return false;
+            return Ok(false);
+        }
+        let mnemonic = self.program[self.program_counter].mnemonic();
+        self.writes.clear();
+        let result = self.execute();
+        if result.is_ok() {
+            self.cycles += 1;
+            *self.opcode_counts.entry(mnemonic).or_insert(0) += 1;
+        }
+        // The program counter is still on the instruction that failed
+        return match (result, self.current_span()) {
+            (Err(error), Some(span)) => Err(VmError::Located { error: Box::new(error), span: *span }),
+            (result, _) => result,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        return self.cycles;
+    }
+
+    pub fn opcode_counts(&self) -> HashMap<&'static str, u64> {
+        return self.opcode_counts.clone();
+    }
+
+    // Execute the instruction at the program counter
+    fn execute(&mut self) -> Result<bool, VmError> {
+        if let Some(reg) = register_operands(&self.program[self.program_counter]).into_iter().find(|reg| *reg as usize >= self.registers.len()) {
+            return Err(VmError::InvalidRegister(reg));
         }
         match self.program[self.program_counter] {
             Instruction::Load(reg, value) => {
                 self.registers[reg as usize] = value;
             }
+            Instruction::Mov(reg_dst, reg_src) => {
+                self.registers[reg_dst as usize] = self.registers[reg_src as usize];
+            }
             Instruction::Add(reg_result, reg_a, reg_b) => {
-                self.registers[reg_result as usize] = self.registers[reg_a as usize] + self.registers[reg_b as usize];
+                let (v_a, v_b) = (self.registers[reg_a as usize], self.registers[reg_b as usize]);
+                let result = self.arithmetic(v_a, v_b, u8::checked_add, u8::wrapping_add, u8::saturating_add)?;
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, v_a.overflowing_add(v_b).1);
             }
             Instruction::Sub(reg_result, reg_a, reg_b) => {
-                self.registers[reg_result as usize] = self.registers[reg_a as usize] - self.registers[reg_b as usize];
+                let (v_a, v_b) = (self.registers[reg_a as usize], self.registers[reg_b as usize]);
+                let result = self.arithmetic(v_a, v_b, u8::checked_sub, u8::wrapping_sub, u8::saturating_sub)?;
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, v_a.overflowing_sub(v_b).1);
             }
             Instruction::Mul(reg_result, reg_a, reg_b) => {
-                self.registers[reg_result as usize] = self.registers[reg_a as usize] * self.registers[reg_b as usize];
+                let (v_a, v_b) = (self.registers[reg_a as usize], self.registers[reg_b as usize]);
+                let result = self.arithmetic(v_a, v_b, u8::checked_mul, u8::wrapping_mul, u8::saturating_mul)?;
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, v_a.overflowing_mul(v_b).1);
             }
-            Instruction::Div(reg_result, reg_a, reg_b) => {
-                self.registers[reg_result as usize] = self.registers[reg_a as usize] / self.registers[reg_b as usize];
+            Instruction::Div(reg_result, reg_a, reg_b) | Instruction::Mod(reg_result, reg_a, reg_b) => {
+                let divisor = self.registers[reg_b as usize];
+                if divisor == 0 {
+                    if self.div_zero_mode == DivZeroMode::Fault {
+                        return Err(VmError::DivByZero { pc: self.program_counter });
+                    }
+                    self.registers[reg_result as usize] = 0;
+                    self.error_flag = true;
+                } else {
+                    self.registers[reg_result as usize] = match self.program[self.program_counter] {
+                        Instruction::Mod(_, _, _) => self.registers[reg_a as usize] % divisor,
+                        _ => self.registers[reg_a as usize] / divisor,
+                    };
+                }
+                self.set_flags(self.registers[reg_result as usize], false);
             }
             Instruction::Cmp(reg_result, reg_a, reg_b) => {
                 let v_a = self.registers[reg_a as usize];
@@ -69,14 +505,88 @@ impl VM {
                 } else {
                     self.registers[reg_result as usize] = 2;
                 }
+                // Flags of [arg1] - [arg2]
+                self.set_flags(v_a.wrapping_sub(v_b), v_a < v_b);
+            }
+            Instruction::CmpF(reg_a, reg_b) => {
+                let v_a = self.registers[reg_a as usize];
+                let v_b = self.registers[reg_b as usize];
+                self.set_flags(v_a.wrapping_sub(v_b), v_a < v_b);
+            }
+            Instruction::MulWide(reg_high, reg_low, reg_a, reg_b) => {
+                let result = self.registers[reg_a as usize] as u16 * self.registers[reg_b as usize] as u16;
+                let (high, low) = (((result >> 8) & 0xFF) as u8, (result & 0xFF) as u8);
+                self.registers[reg_high as usize] = high;
+                self.registers[reg_low as usize] = low;
+                self.set_flags(high, high != 0);
+                if low != 0 {
+                    self.flags &= !FLAG_ZERO;
+                }
+            }
+            Instruction::FMul(reg_result, reg_a, reg_b) => {
+                let result = (self.registers[reg_a as usize] as u16 * self.registers[reg_b as usize] as u16) >> 4;
+                let saturated = min(result, u8::MAX as u16) as u8;
+                self.registers[reg_result as usize] = saturated;
+                self.set_flags(saturated, result > u8::MAX as u16);
+            }
+            Instruction::Min(reg_result, reg_a, reg_b) => {
+                let result = min(self.registers[reg_a as usize], self.registers[reg_b as usize]);
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::Max(reg_result, reg_a, reg_b) => {
+                let result = max(self.registers[reg_a as usize], self.registers[reg_b as usize]);
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::And(reg_result, reg_a, reg_b) => {
+                let result = self.registers[reg_a as usize] & self.registers[reg_b as usize];
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::Or(reg_result, reg_a, reg_b) => {
+                let result = self.registers[reg_a as usize] | self.registers[reg_b as usize];
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::Xor(reg_result, reg_a, reg_b) => {
+                let result = self.registers[reg_a as usize] ^ self.registers[reg_b as usize];
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::Not(reg_result, reg_a) => {
+                let result = !self.registers[reg_a as usize];
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, false);
+            }
+            Instruction::Shl(reg_result, reg_a, reg_b) => {
+                let (value, amount) = (self.registers[reg_a as usize], (self.registers[reg_b as usize] % 8) as u32);
+                let result = value.wrapping_shl(amount);
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, (value as u16) << amount > u8::MAX as u16);
+            }
+            Instruction::Shr(reg_result, reg_a, reg_b) => {
+                let (value, amount) = (self.registers[reg_a as usize], (self.registers[reg_b as usize] % 8) as u32);
+                let result = value.wrapping_shr(amount);
+                self.registers[reg_result as usize] = result;
+                self.set_flags(result, value & ((1 << amount) - 1) != 0);
+            }
+            Instruction::CMov(reg_result, reg_value, reg_cond) => {
+                if self.registers[reg_cond as usize] != 0 {
+                    self.registers[reg_result as usize] = self.registers[reg_value as usize];
+                }
             }
             Instruction::SPush(reg_addr1, reg_addr2, reg_value) => {
-                let map = self.stack_memory_map.get_mut(0).unwrap();
-                self.stack[map.0] = self.registers[reg_value as usize];
-                if reg_addr1 < REGISTERS as u8 {
+                match self.stack_memory_map.get(0) {
+                    Some(map) => self.check_writable(map.0)?,
+                    None => return Err(VmError::StackFull),
+                }
+                self.write_memory(self.stack_memory_map[0].0, self.registers[reg_value as usize]);
+                let map = &mut self.stack_memory_map[0];
+                if reg_addr1 != IGNORE {
                     self.registers[reg_addr1 as usize] = ((map.0 >> 8) & 0xFF) as u8;
                 }
-                if reg_addr2 < REGISTERS as u8 {
+                if reg_addr2 != IGNORE {
                     self.registers[reg_addr2 as usize] = (map.0 & 0xFF) as u8;
                 }
                 if map.1 > 1 {
@@ -85,23 +595,35 @@ impl VM {
                 } else {
                     self.stack_memory_map.remove(0);
                 }
+                self.max_stack_used = max(self.max_stack_used, self.stack_used());
             }
             Instruction::SPop(reg_addr1, reg_addr2, reg_value) => {
-                let address = (self.registers[reg_addr1 as usize] as usize) << 8 + self.registers[reg_addr2 as usize] as usize;
+                let address = self.stack_address(reg_addr1, reg_addr2)?;
+                self.check_writable(address)?;
                 let value = self.stack[address];
                 self.stack_memory_map.push((address, 1));
                 self.registers[reg_value as usize] = value;
             }
             Instruction::SCopy(reg_addr1, reg_addr2, reg_value) => {
-                let address = (self.registers[reg_addr1 as usize] as usize) << 8 + self.registers[reg_addr2 as usize] as usize;
+                let address = self.stack_address(reg_addr1, reg_addr2)?;
                 let value = self.stack[address];
                 self.registers[reg_value as usize] = value;
             }
             Instruction::SRep(reg_addr1, reg_addr2, reg_value) => {
-                let address = (self.registers[reg_addr1 as usize] as usize) << 8 + self.registers[reg_addr2 as usize] as usize;
-                self.stack[address] = self.registers[reg_value as usize];
+                let address = self.stack_address(reg_addr1, reg_addr2)?;
+                self.check_writable(address)?;
+                self.write_memory(address, self.registers[reg_value as usize]);
                 // TODO: Check if not used
             }
+            Instruction::LoadInd(reg_addr1, reg_addr2, reg_value) => {
+                let address = self.stack_address(reg_addr1, reg_addr2)?;
+                self.registers[reg_value as usize] = self.stack[address];
+            }
+            Instruction::StoreInd(reg_addr1, reg_addr2, reg_value) => {
+                let address = self.stack_address(reg_addr1, reg_addr2)?;
+                self.check_writable(address)?;
+                self.write_memory(address, self.registers[reg_value as usize]);
+            }
             Instruction::REq(reg1, reg2) => {
                 if self.registers[reg1 as usize] != self.registers[reg2 as usize] {
                     self.program_counter += 1;
@@ -112,38 +634,699 @@ impl VM {
                     self.program_counter += 1;
                 }
             }
+            Instruction::Jump8(byte) => {
+                self.program_counter = self.check_jump(byte as usize)?;
+                return Ok(true);
+            }
+            Instruction::RJump8(reg) => {
+                self.program_counter = self.check_jump(self.registers[reg as usize] as usize)?;
+                return Ok(true);
+            }
             Instruction::Jump16(byte1, byte2) => {
-                self.program_counter = (byte1 << 8 + byte2) as usize;
+                self.program_counter = self.check_jump(((byte1 as usize) << 8) | (byte2 as usize))?;
+                return Ok(true);
             }
             Instruction::RJump16(reg1, reg2) => {
-                self.program_counter = (self.registers[reg1 as usize] << 8 + self.registers[reg2 as usize]) as usize;
+                self.program_counter = self.check_jump(self.address(reg1, reg2))?;
+                return Ok(true);
+            }
+            Instruction::JzReg(reg, byte1, byte2) => {
+                if self.registers[reg as usize] == 0 {
+                    self.program_counter = self.check_jump(((byte1 as usize) << 8) | (byte2 as usize))?;
+                    return Ok(true);
+                }
+            }
+            Instruction::JnzReg(reg, byte1, byte2) => {
+                if self.registers[reg as usize] != 0 {
+                    self.program_counter = self.check_jump(((byte1 as usize) << 8) | (byte2 as usize))?;
+                    return Ok(true);
+                }
+            }
+            Instruction::Jz(byte1, byte2) | Instruction::Jnz(byte1, byte2) | Instruction::Jc(byte1, byte2) => {
+                let jump = match self.program[self.program_counter] {
+                    Instruction::Jz(_, _) => self.zero_flag(),
+                    Instruction::Jnz(_, _) => !self.zero_flag(),
+                    _ => self.carry_flag(),
+                };
+                if jump {
+                    self.program_counter = self.check_jump(((byte1 as usize) << 8) | (byte2 as usize))?;
+                    return Ok(true);
+                }
+            }
+            Instruction::Halt() => return Ok(false),
+            Instruction::Out(reg) => {
+                if let Err(err) = self.output.write_all(&[self.registers[reg as usize]]) {
+                    return Err(VmError::Io(err.kind()));
+                }
+            }
+            Instruction::In(reg) => {
+                let mut byte = [0];
+                match self.input.read(&mut byte) {
+                    Ok(0) => {
+                        self.registers[reg as usize] = 0;
+                        self.input_ended = true;
+                    }
+                    Ok(_) => self.registers[reg as usize] = byte[0],
+                    Err(err) => return Err(VmError::Io(err.kind())),
+                }
+            }
+            Instruction::Trap() => {
+                let dump = format!("--- Trap at 0x{:04X} ---\n{}{}", self.program_counter, self.dump_registers(), self.dump_memory_map());
+                if let Err(err) = self.trace.write_all(dump.as_bytes()) {
+                    return Err(VmError::Io(err.kind()));
+                }
+            }
+            Instruction::Custom(opcode, reg_a, reg_b, reg_c) => {
+                let handler = match self.handlers.iter().find(|handler| handler.opcode() == opcode) {
+                    Some(handler) => handler,
+                    None => return Err(VmError::UnknownOpcode(opcode)),
+                };
+                let mut state = VmState::new([reg_a, reg_b, reg_c], &mut self.registers, &mut self.stack, &mut self.writes, &self.read_only, self.program_counter);
+                handler.execute(&mut state)?;
+                if let Some(target) = state.jumped() {
+                    self.program_counter = self.check_jump(target)?;
+                    return Ok(true);
+                }
             }
-            Instruction::Halt() => return false,
         }
         self.program_counter += 1;
-        return true;
+        return Ok(true);
+    }
+
+    pub fn run(&mut self) -> HaltReason {
+        return self.run_with_limit(u64::MAX);
+    }
+
+    // Same as run but stops after [max_steps] instructions
+    // An empty program finishes right away without executing anything, the registers and the stack are left untouched
+    pub fn run_with_limit(&mut self, max_steps: u64) -> HaltReason {
+        if self.program.is_empty() {
+            return HaltReason::Finished;
+        }
+        let mut steps: u64 = 0;
+        loop {
+            // Running off the end after the last allowed step isn't a limit
+            if steps >= max_steps && self.program_counter < self.program.len() {
+                return HaltReason::CycleLimit;
+            }
+            match self.run_once() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => return HaltReason::Fault(err),
+            }
+            steps += 1;
+        }
+        // HALT stops without moving the counter so it is still in the program
+        return if self.program_counter >= self.program.len() {
+            HaltReason::Finished
+        } else {
+            HaltReason::Halted
+        }
+    }
+
+    // Same as run but records the registers and memory addresses changed by each instruction
+    pub fn run_diff(&mut self) -> (HaltReason, Vec<StepDiff>) {
+        let mut steps = vec![];
+        while self.program_counter < self.program.len() {
+            let address = self.program_counter;
+            let instruction = self.program[address].clone();
+            let registers = self.registers.clone();
+            let result = self.run_once();
+
+            let mut changes = vec![];
+            for i in 0..self.registers.len() {
+                if registers[i] != self.registers[i] {
+                    changes.push(Change::Register(i as u8, registers[i], self.registers[i]));
+                }
+            }
+            // Only the written addresses can change, an address written twice keeps its first previous value
+            let mut written: Vec<(usize, u8)> = vec![];
+            for (address, old) in self.writes.iter() {
+                if !written.iter().any(|(written_address, _)| written_address == address) {
+                    written.push((*address, *old));
+                }
+            }
+            written.sort_by_key(|(address, _)| *address);
+            for (address, old) in written {
+                if old != self.stack[address] {
+                    changes.push(Change::Memory(address, old, self.stack[address]));
+                }
+            }
+            steps.push(StepDiff { address, instruction, changes });
+
+            match result {
+                Ok(true) => {}
+                Ok(false) => return (HaltReason::Halted, steps),
+                Err(err) => return (HaltReason::Fault(err), steps),
+            }
+        }
+        return (HaltReason::Finished, steps);
+    }
+
+    pub fn register(&self, idx: usize) -> Option<u8> {
+        return self.registers.get(idx).copied();
+    }
+
+    pub fn set_register(&mut self, idx: usize, val: u8) -> Result<(), VmError> {
+        match self.registers.get_mut(idx) {
+            Some(reg) => *reg = val,
+            None => return Err(VmError::InvalidRegister(min(idx, u8::MAX as usize) as u8)),
+        }
+        return Ok(());
+    }
+
+    pub fn memory(&self, addr: usize) -> Option<u8> {
+        return self.stack.get(addr).copied();
+    }
+
+    pub fn set_memory(&mut self, addr: usize, val: u8) -> Result<(), VmError> {
+        match self.stack.get_mut(addr) {
+            Some(value) => *value = val,
+            None => return Err(VmError::InvalidMemoryAddress(addr)),
+        }
+        return Ok(());
+    }
+
+    pub fn program_counter(&self) -> usize {
+        return self.program_counter;
+    }
+
+    // True if [addr] isn't in any free region
+    pub fn is_allocated(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        return !self.stack_memory_map.iter().any(|(ptr, size)| addr >= *ptr && addr < ptr + size);
+    }
+
+    // Number of bytes of the stack currently allocated
+    fn stack_used(&self) -> usize {
+        return self.stack.len() - self.stack_memory_map.iter().map(|(_, size)| size).sum::<usize>();
+    }
+
+    pub fn max_stack_used(&self) -> usize {
+        return self.max_stack_used;
+    }
+
+    pub fn set_spans(&mut self, spans: Vec<Span>) {
+        self.spans = spans;
+    }
+
+    // Source position of the instruction at the program counter
+    pub fn current_span(&self) -> Option<&Span> {
+        return self.spans.get(self.program_counter);
+    }
+
+    // Put the VM back in its initial state, the program, the handlers and the modes are kept
+    // Memory preloaded with with_initial_memory is loaded again and stays allocated
+    pub fn reset(&mut self) {
+        self.stack.fill(0);
+        self.stack_memory_map = vec![(0, self.stack.len())];
+        self.registers.fill(0);
+        self.program_counter = 0;
+        self.max_stack_used = 0;
+        self.error_flag = false;
+        self.flags = 0;
+        self.input_ended = false;
+        self.cycles = 0;
+        self.opcode_counts.clear();
+        self.load_initial_memory();
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot {
+        return VmSnapshot {
+            registers: self.registers.clone(),
+            stack: self.stack.clone(),
+            stack_memory_map: self.stack_memory_map.clone(),
+            program_counter: self.program_counter,
+            max_stack_used: self.max_stack_used,
+            error_flag: self.error_flag,
+            flags: self.flags,
+        }
+    }
+
+    // The snapshot should come from a VM with the same configuration
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.registers = snapshot.registers.clone();
+        self.stack = snapshot.stack.clone();
+        self.stack_memory_map = snapshot.stack_memory_map.clone();
+        self.program_counter = snapshot.program_counter;
+        self.max_stack_used = snapshot.max_stack_used;
+        self.error_flag = snapshot.error_flag;
+        self.flags = snapshot.flags;
+    }
+
+    // For each input: reset the VM, load the input in [input_regs], run the program and read [output_reg]
+    // An input gives an error if a register doesn't exist or the program faults
+    pub fn run_batch<const N: usize>(&mut self, inputs: &[[u8; N]], input_regs: &[u8], output_reg: u8) -> Vec<Result<u8, VmError>> {
+        let mut outputs = vec![];
+        for input in inputs.iter() {
+            outputs.push(self.run_input(input, input_regs, output_reg));
+        }
+        return outputs;
+    }
+
+    fn run_input(&mut self, input: &[u8], input_regs: &[u8], output_reg: u8) -> Result<u8, VmError> {
+        self.reset();
+        for (reg, value) in input_regs.iter().zip(input.iter()) {
+            self.set_register(*reg as usize, *value)?;
+        }
+        if output_reg as usize >= self.registers.len() {
+            return Err(VmError::InvalidRegister(output_reg));
+        }
+        return match self.run() {
+            HaltReason::Fault(err) => Err(err),
+            _ => Ok(self.registers[output_reg as usize]),
+        }
+    }
+
+    // Replace the instruction at [addr], the next execution of [addr] runs the new instruction
+    pub fn patch(&mut self, addr: usize, instr: Instruction) -> Result<(), VmError> {
+        if addr >= self.program.len() {
+            return Err(VmError::InvalidProgramAddress(addr));
+        }
+        self.program[addr] = instr;
+        return Ok(());
+    }
+
+    pub fn format_registers(&self, radix: Radix) -> String {
+        let mut result = String::new();
+        for i in 0..self.registers.len() {
+            result.push_str(&format!("[{:X}]: {}{}\n", i, radix.prefix(), radix.format(self.registers[i])));
+        }
+        return result;
+    }
+
+    pub fn dump_registers(&self) -> String {
+        return self.format_registers(Radix::default());
+    }
+
+    pub fn print_registers(&self) {
+        print!("{}", self.dump_registers());
+    }
+
+    pub fn dump_memory_map(&self) -> String {
+        let mut result = String::new();
+        for (ptr, size) in self.stack_memory_map.iter() {
+            result.push_str(&format!("Free: 0x{:04X} ({} Byte)\n", ptr, size));
+        }
+        return result;
+    }
+
+    pub fn print_memory_map(&self) {
+        print!("{}", self.dump_memory_map());
+    }
+
+    pub fn format_memory(&self, rows: usize, radix: Radix) -> String {
+        let mut result = String::new();
+        for i in 0..min((self.stack.len() + 15) / 16, rows) {
+            result.push_str(&format!("[{:03X}]:", i));
+            for j in 0..min(16, self.stack.len() - i * 16) {
+                result.push_str(&format!(" {}", radix.format(self.stack[i * 16 + j])));
+            }
+            result.push('\n');
+        }
+        return result;
+    }
+
+    pub fn dump_memory(&self, rows: usize) -> String {
+        return self.format_memory(rows, Radix::default());
+    }
+
+    pub fn print_memory(&self, rows: usize) {
+        print!("{}", self.dump_memory(rows));
     }
 
-    pub fn run(&mut self) {
-        while self.run_once() {
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    // Output that can still be read once it was given to a VM
+    #[derive(Clone)]
+    pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Capture {
 
+        pub fn new() -> Capture {
+            return Capture(Rc::new(RefCell::new(vec![])));
         }
+
+        pub fn bytes(&self) -> Vec<u8> {
+            return self.0.borrow().clone();
+        }
+
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    // Run a program on an initial state (registers and memory preloaded at address 0) for at most [max_steps] instructions
+    fn run_with_input(program: &[Instruction], registers: &[u8; REGISTERS], memory: &[u8], max_steps: u64) -> (HaltReason, VM) {
+        let mut vm = VM::with_initial_memory(program.to_vec(), memory, 0).expect("The input memory doesn't fit in the stack");
+        vm.registers = registers.to_vec();
+        let reason = vm.run_with_limit(max_steps);
+        return (reason, vm);
+    }
+
+    // Panic if the two programs don't stop for the same reason with the same registers and memory for every input
+    pub fn assert_programs_equivalent(a: &[Instruction], b: &[Instruction], inputs: &[([u8; REGISTERS], Vec<u8>)], max_steps: u64) {
+        for (i, (registers, memory)) in inputs.iter().enumerate() {
+            let (reason_a, vm_a) = run_with_input(a, registers, memory, max_steps);
+            let (reason_b, vm_b) = run_with_input(b, registers, memory, max_steps);
+            assert_eq!(reason_a, reason_b, "Halt reasons differ for input {}", i);
+            assert_eq!(vm_a.registers, vm_b.registers, "Registers differ for input {}", i);
+            if let Some(address) = (0..vm_a.stack.len()).find(|address| vm_a.stack[*address] != vm_b.stack[*address]) {
+                panic!("Memory differs at 0x{:04X} for input {} (0x{:02X} != 0x{:02X})", address, i, vm_a.stack[address], vm_b.stack[address]);
+            }
+        }
+    }
+
+    fn inputs(values: &[u8]) -> Vec<([u8; REGISTERS], Vec<u8>)> {
+        return values.iter().map(|value| {
+            let mut registers = [0; REGISTERS];
+            registers[0] = *value;
+            (registers, vec![*value])
+        }).collect();
+    }
+
+    #[test]
+    fn optimized_expression_is_equivalent() {
+        // x * 2 and x + x, [r1] is 0 at the end of both
+        let unoptimized = [Instruction::Load(1, 2), Instruction::Mul(0, 0, 1), Instruction::Load(1, 0)];
+        let optimized = [Instruction::Add(0, 0, 0)];
+        assert_programs_equivalent(&unoptimized, &optimized, &inputs(&[0, 1, 100, 200]), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Halt reasons differ")]
+    fn faulting_program_is_not_equivalent_to_a_halting_one() {
+        // Both leave [r0] at 0 but the first one divides by 0
+        let faulting = [Instruction::Div(0, 0, 1)];
+        let halting = [Instruction::Load(0, 0)];
+        assert_programs_equivalent(&faulting, &halting, &inputs(&[0]), 100);
+    }
+
+    #[test]
+    fn initial_memory_is_readable_and_not_reused() {
+        let program = vec![
+            Instruction::Load(0, 0x01),
+            Instruction::Load(1, 0x02),
+            Instruction::SCopy(0, 1, 2),
+            Instruction::Load(1, 0x03),
+            Instruction::SCopy(0, 1, 3),
+            Instruction::SPush(4, 5, 2),
+        ];
+        let mut vm = VM::with_initial_memory(program, &[10, 20, 30], 0x0101).unwrap();
+        assert!(vm.is_allocated(0x0101) && vm.is_allocated(0x0103));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!(vm.register(2), Some(20));
+        assert_eq!(vm.register(3), Some(30));
+        // The first free address is still 0
+        assert_eq!((vm.register(4), vm.register(5)), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn initial_memory_past_the_stack_is_an_error() {
+        assert!(matches!(VM::with_initial_memory(vec![], &[1, 2, 3], 0xFFFF), Err(VmError::InvalidMemoryAddress(0x10001))));
+        assert!(matches!(VM::with_read_only_memory(vec![], &[1, 2], 0xFFFF), Err(VmError::InvalidMemoryAddress(0x10000))));
+        assert!(VM::with_initial_memory(vec![], &[1], 0xFFFF).is_ok());
     }
 
-    pub fn print_registers(&mut self) {
-        for i in 0..REGISTERS {
-            println!("[{:X}]: 0x{:02X}", i, self.registers[i]);
+    #[test]
+    fn trap_writes_the_dump_to_the_trace() {
+        let mut vm = VM::new(vec![Instruction::Load(3, 0x2A), Instruction::Trap(), Instruction::Load(4, 1)]);
+        let output = Capture::new();
+        let trace = Capture::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_trace(Box::new(trace.clone()));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        let dump = String::from_utf8(trace.bytes()).unwrap();
+        assert!(dump.starts_with("--- Trap at 0x0001 ---\n"));
+        assert!(dump.contains("[3]: 0x2A\n"));
+        assert!(dump.contains("Free: 0x0000 (65536 Byte)\n"));
+        assert!(output.bytes().is_empty());
+        // The program continues after the trap
+        assert_eq!(vm.register(4), Some(1));
+    }
+
+    #[test]
+    fn cmpf_only_sets_the_flags() {
+        let mut vm = VM::new(vec![Instruction::Load(0, 3), Instruction::Load(1, 5), Instruction::CmpF(0, 1)]);
+        vm.run();
+        assert!(vm.carry_flag() && vm.sign_flag() && !vm.zero_flag());
+        assert_eq!(vm.registers, [vec![3, 5], vec![0; REGISTERS - 2]].concat());
+
+        let mut vm = VM::new(vec![
+            Instruction::Load(0, 3),
+            Instruction::Load(1, 5),
+            Instruction::CmpF(0, 1),
+            Instruction::Jc(0, 6),      // 3 < 5
+            Instruction::Load(2, 0xFF),
+            Instruction::Halt(),
+            Instruction::Load(2, 1),
+            Instruction::CmpF(1, 1),
+            Instruction::Jz(0, 11),     // 5 == 5
+            Instruction::Load(3, 0xFF),
+            Instruction::Halt(),
+            Instruction::Load(3, 1),
+        ]);
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!(vm.registers, [vec![3, 5, 1, 1], vec![0; REGISTERS - 4]].concat());
+    }
+
+    #[test]
+    fn run_batch_computes_each_input() {
+        // r2 = r0 * r1 + 1
+        let mut vm = VM::new(vec![Instruction::Mul(2, 0, 1), Instruction::Load(3, 1), Instruction::Add(2, 2, 3)]);
+        let outputs = vm.run_batch(&[[2, 3], [0, 9], [4, 4]], &[0, 1], 2);
+        assert_eq!(outputs, vec![Ok(7), Ok(1), Ok(17)]);
+    }
+
+    #[test]
+    fn run_batch_keeps_the_initial_memory() {
+        // Look up the table at 0x0010 with the index in r1
+        let program = vec![Instruction::Load(0, 0), Instruction::Load(2, 0x10), Instruction::Add(1, 1, 2), Instruction::SCopy(0, 1, 3)];
+        let mut vm = VM::with_initial_memory(program, &[5, 6, 7], 0x0010).unwrap();
+        assert_eq!(vm.run_batch(&[[2], [0], [1]], &[1], 3), vec![Ok(7), Ok(5), Ok(6)]);
+        assert!(vm.is_allocated(0x0010) && vm.is_allocated(0x0012));
+    }
+
+    #[test]
+    fn run_batch_reports_errors() {
+        let mut vm = VM::new(vec![Instruction::Div(2, 0, 1)]);
+        assert_eq!(vm.run_batch(&[[6, 0], [6, 3]], &[0, 1], 2), vec![Err(VmError::DivByZero { pc: 0 }), Ok(2)]);
+        assert_eq!(vm.run_batch(&[[6, 3]], &[0, 20], 2), vec![Err(VmError::InvalidRegister(20))]);
+        assert_eq!(vm.run_batch(&[[6, 3]], &[0, 1], 20), vec![Err(VmError::InvalidRegister(20))]);
+    }
+
+    #[test]
+    fn fault_without_spans_is_not_located() {
+        let mut vm = VM::new(vec![Instruction::Div(2, 0, 1)]);
+        assert_eq!(vm.run(), HaltReason::Fault(VmError::DivByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn read_only_memory_can_only_be_read() {
+        let program = |instruction: Instruction| vec![Instruction::Load(0, 0), Instruction::Load(1, 1), Instruction::Load(2, 9), Instruction::SCopy(0, 1, 3), instruction];
+        for instruction in [Instruction::SRep(0, 1, 2), Instruction::StoreInd(0, 1, 2), Instruction::SPop(0, 1, 2)].iter() {
+            let mut vm = VM::with_read_only_memory(program(instruction.clone()), &[7, 8], 0).unwrap();
+            assert_eq!(vm.run(), HaltReason::Fault(VmError::WriteToReadOnly { addr: 1 }), "{:?}", instruction);
+            assert_eq!(vm.register(3), Some(8));
+            assert_eq!(vm.memory(1), Some(8));
         }
     }
 
-    pub fn print_memory(&mut self, rows: usize) {
-        for i in 0..min(STACK_SIZE / 16, rows) {
-            print!("[{:03X}]:", i);
-            for j in 0..16 {
-                print!(" {:02X}", self.stack[i * 16 + j]);
+    #[test]
+    fn read_only_memory_stays_allocated_after_reset() {
+        let mut vm = VM::with_read_only_memory(vec![Instruction::Load(2, 9), Instruction::SPush(0, 1, 2)], &[7, 7], 0).unwrap();
+        vm.reset();
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!((vm.memory(0), vm.memory(1), vm.memory(2)), (Some(7), Some(7), Some(9)));
+        assert_eq!((vm.register(0), vm.register(1)), (Some(0), Some(2)));
+    }
+
+    #[test]
+    fn jump16_uses_both_address_bytes() {
+        // Lands at 0x0102, the filler in between would set r0
+        let jump = |first: Vec<Instruction>| {
+            let mut program = first;
+            while program.len() < 0x0102 {
+                program.push(Instruction::Load(0, 0xFF));
             }
-            println!();
+            program.push(Instruction::Load(1, 1));
+            program.push(Instruction::Halt());
+            return program;
+        };
+        let mut vm = VM::new(jump(vec![Instruction::Jump16(0x01, 0x02)]));
+        assert_eq!(vm.run(), HaltReason::Halted);
+        assert_eq!((vm.register(0), vm.register(1)), (Some(0), Some(1)));
+
+        let mut vm = VM::new(jump(vec![Instruction::Load(2, 0x01), Instruction::Load(3, 0x02), Instruction::RJump16(2, 3)]));
+        assert_eq!(vm.run(), HaltReason::Halted);
+        assert_eq!((vm.register(0), vm.register(1)), (Some(0), Some(1)));
+    }
+
+    #[test]
+    fn stack_instructions_use_both_address_bytes() {
+        // The first free address is 0x0102
+        let program = vec![
+            Instruction::Load(2, 7),
+            Instruction::SPush(0, 1, 2),
+            Instruction::SCopy(0, 1, 3),
+            Instruction::Load(2, 8),
+            Instruction::SRep(0, 1, 2),
+            Instruction::SPop(0, 1, 4),
+        ];
+        let mut vm = VM::with_initial_memory(program, &[0; 0x0102], 0).unwrap();
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!((vm.register(0), vm.register(1)), (Some(0x01), Some(0x02)));
+        assert_eq!((vm.register(3), vm.register(4)), (Some(7), Some(8)));
+        assert_eq!((vm.memory(0x0002), vm.memory(0x0102)), (Some(0), Some(8)));
+        assert!(!vm.is_allocated(0x0102));
+    }
+
+    #[test]
+    fn min_and_max_are_unsigned() {
+        // (a, b, min, max): equal, less and greater operands, 0x80 isn't negative
+        for (a, b, low, high) in [(7, 7, 7, 7), (3, 0x80, 3, 0x80), (0xFF, 1, 1, 0xFF)].iter() {
+            let mut vm = VM::new(vec![Instruction::Load(0, *a), Instruction::Load(1, *b), Instruction::Min(2, 0, 1), Instruction::Max(3, 0, 1)]);
+            assert_eq!(vm.run(), HaltReason::Finished);
+            assert_eq!((vm.register(2), vm.register(3)), (Some(*low), Some(*high)), "{} {}", a, b);
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn mul_wide_keeps_the_high_byte() {
+        let mut vm = VM::new(vec![Instruction::Load(0, 200), Instruction::Load(1, 200), Instruction::MulWide(2, 3, 0, 1)]);
+        assert_eq!(vm.run(), HaltReason::Finished);
+        // 200 * 200 = 40000 = 0x9C40
+        assert_eq!((vm.register(2), vm.register(3)), (Some(0x9C), Some(0x40)));
+    }
+
+    #[test]
+    fn fixed_point_mul() {
+        // Q4.4: (a, b, a * b), 8.0 * 4.0 saturates to 15.9375
+        for (a, b, product) in [(0x18, 0x20, 0x30), (0x80, 0x40, 0xFF), (0x10, 0x00, 0x00)].iter() {
+            let mut vm = VM::new(vec![Instruction::Load(0, *a), Instruction::Load(1, *b), Instruction::FMul(2, 0, 1)]);
+            assert_eq!(vm.run(), HaltReason::Finished);
+            assert_eq!(vm.register(2), Some(*product), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn run_diff_lists_the_memory_writes() {
+        let program = vec![
+            Instruction::Load(2, 7),
+            Instruction::SPush(0, 1, 2),
+            Instruction::Load(2, 9),
+            Instruction::SRep(0, 1, 2),
+            Instruction::SRep(0, 1, 2),
+            Instruction::Load(1, 5),
+            Instruction::StoreInd(0, 1, 2),
+        ];
+        let (reason, steps) = VM::new(program).run_diff();
+        assert_eq!(reason, HaltReason::Finished);
+        let changes: Vec<String> = steps.iter().map(|step| format!("{:?}", step.changes)).collect();
+        assert_eq!(changes, vec![
+            "[r2: 0x00 -> 0x07]",
+            "[[0x0000]: 0x00 -> 0x07]",
+            "[r2: 0x07 -> 0x09]",
+            "[[0x0000]: 0x07 -> 0x09]",
+            "[]",
+            "[r1: 0x00 -> 0x05]",
+            "[[0x0005]: 0x00 -> 0x09]",
+        ]);
+    }
+
+    #[test]
+    fn configured_sizes_are_used() {
+        let config = VmConfig { stack_size: 4, registers: 4 };
+        let mut vm = VM::with_config(vec![Instruction::SPush(0, 1, 2); 5], config);
+        assert_eq!(vm.run(), HaltReason::Fault(VmError::StackFull));
+        assert_eq!((vm.program_counter(), vm.memory(3), vm.memory(4)), (4, Some(0), None));
+        assert_eq!((vm.register(3), vm.register(4)), (Some(0), None));
+        let mut vm = VM::with_config(vec![Instruction::Load(4, 1)], VmConfig { stack_size: 4, registers: 4 });
+        assert_eq!(vm.run(), HaltReason::Fault(VmError::InvalidRegister(4)));
+    }
+
+    #[test]
+    fn stack_size_is_clamped() {
+        // Empty stack: 1 byte
+        let mut vm = VM::with_config(vec![Instruction::Load(2, 7), Instruction::SPush(0, 1, 2), Instruction::SPush(0, 1, 2)], VmConfig { stack_size: 0, ..VmConfig::default() });
+        assert_eq!(vm.run(), HaltReason::Fault(VmError::StackFull));
+        assert_eq!((vm.program_counter(), vm.memory(0), vm.memory(1)), (2, Some(7), None));
+        // Past 2^16: the addressable bytes
+        let vm = VM::with_config(vec![], VmConfig { stack_size: STACK_SIZE + 1, ..VmConfig::default() });
+        assert_eq!((vm.memory(STACK_SIZE - 1), vm.memory(STACK_SIZE)), (Some(0), None));
+    }
+
+    #[test]
+    fn alu_instructions_set_the_flags() {
+        // (instruction on r0 = 0xF0 and r1, r1, flags)
+        let cases = [
+            (Instruction::And(2, 0, 1), 0x0F, FLAG_ZERO),
+            (Instruction::Or(2, 0, 1), 0x0F, FLAG_SIGN),
+            (Instruction::Xor(2, 0, 1), 0xF0, FLAG_ZERO),
+            (Instruction::Not(2, 0), 0x00, 0),
+            (Instruction::Min(2, 0, 1), 0x00, FLAG_ZERO),
+            (Instruction::Max(2, 0, 1), 0x00, FLAG_SIGN),
+            (Instruction::Shl(2, 0, 1), 0x01, FLAG_CARRY | FLAG_SIGN),
+            (Instruction::Shl(2, 0, 1), 0x08, FLAG_SIGN),
+            (Instruction::Shr(2, 0, 1), 0x04, 0),
+            (Instruction::Shr(2, 0, 1), 0x05, FLAG_CARRY),
+            (Instruction::FMul(2, 0, 1), 0x20, FLAG_CARRY | FLAG_SIGN),
+            (Instruction::FMul(2, 0, 1), 0x00, FLAG_ZERO),
+            (Instruction::MulWide(2, 3, 0, 1), 0x02, FLAG_CARRY),
+            (Instruction::MulWide(2, 3, 0, 1), 0x10, FLAG_CARRY),
+            (Instruction::MulWide(2, 3, 1, 1), 0x0F, 0),
+            (Instruction::MulWide(2, 3, 0, 1), 0x00, FLAG_ZERO),
+        ];
+        for (instruction, b, flags) in cases.iter() {
+            let mut vm = VM::new(vec![Instruction::Load(0, 0xF0), Instruction::Load(1, *b), instruction.clone()]);
+            assert_eq!(vm.run(), HaltReason::Finished);
+            assert_eq!(vm.flags(), *flags, "{:?} with r1 = 0x{:02X}", instruction, b);
+        }
+    }
+
+    #[test]
+    fn jz_after_and() {
+        // Jumps over the LOAD of r3 when the bits don't overlap
+        let program = |b| vec![
+            Instruction::Load(0, 0xF0),
+            Instruction::Load(1, b),
+            Instruction::And(2, 0, 1),
+            Instruction::Jz(0x00, 0x05),
+            Instruction::Load(3, 1),
+            Instruction::Load(4, 1),
+        ];
+        let mut vm = VM::new(program(0x0F));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!((vm.register(3), vm.register(4)), (Some(0), Some(1)));
+        let mut vm = VM::new(program(0x10));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!((vm.register(3), vm.register(4)), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn reset_clears_the_end_of_input() {
+        let mut vm = VM::new(vec![Instruction::In(0), Instruction::In(1)]);
+        vm.set_input(Box::new(std::io::Cursor::new(vec![7])));
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert!(vm.input_ended());
+        vm.reset();
+        assert!(!vm.input_ended());
+        assert_eq!(vm.run(), HaltReason::Finished);
+        assert_eq!((vm.register(0), vm.input_ended()), (Some(0), true));
+    }
+
+}
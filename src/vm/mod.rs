@@ -1,2 +1,4 @@
 pub mod machine;
-pub mod instruction;
\ No newline at end of file
+pub mod instruction;
+pub mod handler;
+pub mod opcode;
\ No newline at end of file
@@ -0,0 +1,67 @@
+/*
+Goal: Give each instruction a fixed byte for the bytecode.
+    - Values must never change once assigned, saved programs depend on them
+    - Instructions are grouped like in the Instruction enum
+ */
+
+// Register Operation
+pub const LOAD: u8 = 0x00;
+pub const MOV: u8 = 0x01;
+pub const ADD: u8 = 0x02;
+pub const SUB: u8 = 0x03;
+pub const MUL: u8 = 0x04;
+pub const DIV: u8 = 0x05;
+pub const MOD: u8 = 0x06;
+pub const CMP: u8 = 0x07;
+pub const MULW: u8 = 0x08;
+pub const FMUL: u8 = 0x09;
+pub const MIN: u8 = 0x0A;
+pub const MAX: u8 = 0x0B;
+pub const AND: u8 = 0x0C;
+pub const OR: u8 = 0x0D;
+pub const XOR: u8 = 0x0E;
+pub const NOT: u8 = 0x0F;
+pub const SHL: u8 = 0x10;
+pub const SHR: u8 = 0x11;
+pub const CMOV: u8 = 0x12;
+pub const CMPF: u8 = 0x13;
+// Stack Operation
+pub const SPUSH: u8 = 0x20;
+pub const SCOPY: u8 = 0x21;
+pub const SPOP: u8 = 0x22;
+pub const SREP: u8 = 0x23;
+pub const LDI: u8 = 0x24;
+pub const STI: u8 = 0x25;
+// Flow Control
+pub const REQ: u8 = 0x30;
+pub const EQ: u8 = 0x31;
+pub const JUMP8: u8 = 0x32;
+pub const RJUMP8: u8 = 0x33;
+pub const JUMP16: u8 = 0x34;
+pub const RJUMP16: u8 = 0x35;
+pub const JZR: u8 = 0x36;
+pub const JNZR: u8 = 0x37;
+pub const HALT: u8 = 0x38;
+pub const JZ: u8 = 0x39;
+pub const JNZ: u8 = 0x3A;
+pub const JC: u8 = 0x3B;
+// Input/Output
+pub const OUT: u8 = 0x3C;
+pub const IN: u8 = 0x3D;
+// Debug
+pub const TRAP: u8 = 0x40;
+// Extension
+pub const CUSTOM: u8 = 0x50;
+
+// Number of argument bytes following the opcode, None if the opcode doesn't exist
+pub fn operand_count(opcode: u8) -> Option<usize> {
+    return match opcode {
+        HALT | TRAP => Some(0),
+        JUMP8 | RJUMP8 | OUT | IN => Some(1),
+        LOAD | MOV | NOT | CMPF | REQ | EQ | JUMP16 | RJUMP16 | JZ | JNZ | JC => Some(2),
+        ADD | SUB | MUL | DIV | MOD | CMP | FMUL | MIN | MAX | AND | OR | XOR | SHL | SHR | CMOV => Some(3),
+        SPUSH | SCOPY | SPOP | SREP | LDI | STI | JZR | JNZR => Some(3),
+        MULW | CUSTOM => Some(4),
+        _ => None,
+    }
+}
\ No newline at end of file